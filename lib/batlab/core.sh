@@ -0,0 +1,3514 @@
+# batlab core - telemetry collection and sample analysis
+#
+# The part of batlab other tools can build on without the command-line
+# interface: platform detection, sensors and sample collection, the
+# configuration file, annotations, and the functions that reduce sample
+# logs (see run_samples) to summaries, drain rates, buckets and
+# correlations. Source it from a POSIX shell:
+#
+#   . /usr/local/lib/batlab/core.sh
+#   run_samples data/RUN_ID.jsonl | summarize_samples
+#
+# Defining functions and defaults is all sourcing does; set DATA_DIR,
+# CONFIG_FILE and the like afterwards. BATLAB_CORE_VERSION follows
+# semantic versioning independently of the batlab command: functions and
+# output formats only change incompatibly with the major version.
+
+BATLAB_CORE_VERSION="1.1.0"
+
+# Default configuration
+DEFAULT_HZ=1.0
+DATA_DIR="data"
+WORKLOAD_DIR="workload"
+PLUGIN_DIR="plugins"
+BINDIR="bin"
+CONFIG_FILE="${BATLAB_CONFIG:-batlab.toml}"
+HOSTS_FILE="${BATLAB_HOSTS:-hosts.toml}"
+ACTIVE_RUN_FILE=".active_run"
+HEARTBEAT_FILE=".heartbeat"
+WORKLOAD_STATE_FILE=".active_workload"
+RUNNER_STATE_FILE=".active_runner"
+# Root of the sysfs tree read on Linux; the integration tests point it at
+# a recorded tree (tests/fixtures)
+SYSFS="${BATLAB_SYSFS:-/sys}"
+
+# Platform detection. Readings check the platform many times per sample;
+# set PLATFORM once at startup (PLATFORM=$(detect_platform)) and later
+# calls, including those in subshells, answer from it without running uname.
+PLATFORM=""
+
+detect_platform() {
+    if [ -n "$PLATFORM" ]; then
+        echo "$PLATFORM"
+        return 0
+    fi
+    case "$(uname -s)" in
+        FreeBSD) echo "freebsd" ;;
+        OpenBSD) echo "openbsd" ;;
+        NetBSD)  echo "netbsd" ;;
+        Linux)   echo "linux" ;;
+        Darwin)  echo "macos" ;;
+        CYGWIN*|MINGW*|MSYS*) echo "windows" ;;
+        *)       echo "unknown" ;;
+    esac
+}
+
+# Platforms without full backends (Windows under Cygwin or MSYS2, and
+# anything unrecognised) run in best-effort mode: samples carry CPU load
+# and memory where a portable source exists, null readings otherwise, and
+# nothing is made up. Windows has a battery backend (WMI, source "wmi");
+# elsewhere battery readings are null with source "dummy". Reports,
+# validation and the workload runner work as anywhere else.
+is_supported_platform() {
+    case "$(detect_platform)" in
+        freebsd|openbsd|netbsd|linux|macos) return 0 ;;
+    esac
+    return 1
+}
+
+warn_best_effort() {
+    is_supported_platform && return 0
+    if [ "$(detect_platform)" = "windows" ]; then
+        log_warn "Best-effort mode on $(uname -s): battery from WMI; no temperature; CPU load and memory where available"
+        return 0
+    fi
+    log_warn "Best-effort mode on unsupported platform $(uname -s): no battery readings; CPU load and memory where available"
+}
+
+# Logging functions
+# With PORCELAIN set (log/run --porcelain), progress prose is suppressed and
+# emit_event prints machine-readable status lines on stdout instead
+PORCELAIN=""
+
+log_info() {
+    [ -n "$PORCELAIN" ] && return 0
+    printf "[INFO] %s\n" "$1" >&2
+}
+
+log_warn() {
+    if [ -n "$WARN_LOG" ]; then
+        printf '%s\n' "$1" >> "$WARN_LOG"
+        return 0
+    fi
+    printf "[WARN] %s\n" "$1" >&2
+}
+
+log_error() {
+    printf "[ERROR] %s\n" "$1" >&2
+}
+
+log_log() {
+    [ -n "$PORCELAIN" ] && return 0
+    printf "[LOG] %s\n" "$1" >&2
+}
+
+# Warning deduplication while logging. A sensor that fails for good would
+# otherwise warn on every sample and bury the one warning that mattered, so
+# between warn_init and warn_tick final, log_warn appends to WARN_LOG (it is
+# often called from collect_sample's subshell) and warn_tick prints each
+# message the first time it appears, then every WARN_INTERVAL seconds how
+# often it repeated. WARN_COUNTS keeps "total<TAB>unreported<TAB>message"
+# for the session summary.
+WARN_LOG=""
+WARN_COUNTS=""
+WARN_INTERVAL=300
+WARN_REPORTED=""
+
+warn_init() {
+    WARN_LOG=$(mktemp "${TMPDIR:-/tmp}/batlab-warn.XXXXXX") || {
+        WARN_LOG=""
+        return 0
+    }
+    WARN_COUNTS="$WARN_LOG.counts"
+    : > "$WARN_COUNTS"
+    WARN_REPORTED=$(date +%s)
+}
+
+# Call after each sample; with "final" every repeated message is printed
+# with its total and log_warn prints directly again. WARN_COUNTS is left for
+# the summary.
+warn_tick() {
+    [ -n "$WARN_LOG" ] || return 0
+    local now=$(date +%s)
+    local report=""
+    if [ "$1" = final ]; then
+        report=final
+    elif [ $((now - WARN_REPORTED)) -ge "$WARN_INTERVAL" ]; then
+        report=periodic
+    elif [ ! -s "$WARN_LOG" ]; then
+        return 0
+    fi
+
+    local lines
+    lines=$(awk -F'\t' -v counts="$WARN_COUNTS" -v report="$report" \
+        -v since="$(format_duration $((now - WARN_REPORTED)))" '
+        function group(n,   s) {
+            s = ""
+            while (n >= 1000) { s = sprintf(",%03d", n % 1000) s; n = int(n / 1000) }
+            return n s
+        }
+        FILENAME == counts { total[$3] = $1; pending[$3] = $2; order[++k] = $3; next }
+        {
+            if ($0 in total) pending[$0]++
+            else { order[++k] = $0; print $0 }
+            total[$0]++
+        }
+        END {
+            for (i = 1; i <= k; i++) {
+                m = order[i]
+                if (report == "final" && total[m] > 1)
+                    printf "%s for %s samples\n", m, group(total[m])
+                else if (report == "periodic" && pending[m] > 0)
+                    printf "%s for %s samples (%s in the last %s)\n", m, group(total[m]), group(pending[m]), since
+                if (report != "") pending[m] = 0
+                printf "%d\t%d\t%s\n", total[m], pending[m], m > (counts ".new")
+            }
+        }' "$WARN_COUNTS" "$WARN_LOG")
+    if [ -f "$WARN_COUNTS.new" ]; then
+        mv "$WARN_COUNTS.new" "$WARN_COUNTS"
+    fi
+    : > "$WARN_LOG"
+    if [ -n "$report" ]; then
+        WARN_REPORTED=$now
+    fi
+    if [ "$report" = final ]; then
+        rm -f "$WARN_LOG"
+        WARN_LOG=""
+    fi
+    if [ -n "$lines" ]; then
+        printf '%s\n' "$lines" | while IFS= read -r line; do
+            printf "[WARN] %s\n" "$line" >&2
+        done
+    fi
+}
+
+# Print one porcelain status event as a JSON line: emit_event NAME [KEY VALUE]...
+# Values are JSON strings. A key written KEY:num is a numeric field: its
+# value is written as a JSON number (".5" as 0.5), or null when it is not a
+# decimal number, e.g. empty for a reading that could not be taken.
+emit_event() {
+    [ -n "$PORCELAIN" ] || return 0
+    local out="{\"event\": \"$1\", \"t\": \"$(generate_timestamp)\""
+    shift
+    while [ $# -gt 1 ]; do
+        case "$1" in
+            *:num)
+                out="$out, \"${1%:num}\": $(awk -v v="$2" 'BEGIN {
+                    if (v ~ /^-?(0|[1-9][0-9]*)(\.[0-9]+)?$/) print v
+                    else if (v ~ /^[-+]?([0-9]+\.?[0-9]*|\.[0-9]+)$/) printf "%.15g\n", v + 0
+                    else print "null"
+                }')"
+                ;;
+            *) out="$out, \"$1\": \"$(json_escape "$2")\"" ;;
+        esac
+        shift 2
+    done
+    printf '%s}\n' "$out"
+}
+
+# JSON utility functions
+json_escape() {
+    printf '%s' "$1" | sed 's/\\/\\\\/g; s/"/\\"/g'
+}
+
+# Milliseconds since the epoch; whole seconds where date(1) lacks %N (BSD)
+now_ms() {
+    local ns=$(date +%s%N)
+    case "$ns" in
+        *N) echo "$((${ns%N} * 1000))" ;;
+        *)  echo "$((ns / 1000000))" ;;
+    esac
+}
+
+generate_timestamp() {
+    date -u "+%Y-%m-%dT%H:%M:%S.000000000Z"
+}
+
+# Precision of sample timestamps (s, ms, us or ns) and decimal places of
+# sample values, from log --time-precision and --decimals. Unset keeps the
+# nine-digit fraction and values as read, which dominate the size of files
+# written at high sampling rates.
+SAMPLE_TIME_PRECISION=""
+SAMPLE_DECIMALS=""
+
+# Succeed if date(1) prints the fraction of the second (%N); BSD date does not
+date_has_fraction() {
+    case "$(date +%N)" in
+        *[!0-9]*|'') return 1 ;;
+    esac
+}
+
+# Timestamp for a sample at SAMPLE_TIME_PRECISION, nanoseconds when unset.
+# start_logging lowers the precision to s where date_has_fraction fails,
+# so a fraction is only written when it is real.
+sample_timestamp() {
+    local digits
+    case "$SAMPLE_TIME_PRECISION" in
+        s)  date -u "+%Y-%m-%dT%H:%M:%SZ"; return 0 ;;
+        ms) digits=3 ;;
+        us) digits=6 ;;
+        *)  digits=9 ;;
+    esac
+    local t=$(date -u "+%Y-%m-%dT%H:%M:%S.%N")
+    case "$t" in
+        *N) printf '%sZ\n' "${t%.N}"; return 0 ;;
+    esac
+    printf '%s.%sZ\n' "${t%.*}" "$(printf '%s' "${t##*.}" | cut -c1-"$digits")"
+}
+
+# Round the fractional numbers of a sample line to DECIMALS places, dropping
+# trailing zeros; integers such as counters are left alone
+round_sample() {
+    printf '%s\n' "$1" | awk -v d="$2" '{
+        out = ""; s = $0
+        while (match(s, /: -?[0-9]+\.[0-9]+/)) {
+            v = sprintf("%." d "f", substr(s, RSTART + 2, RLENGTH - 2))
+            if (v ~ /\./) { sub(/0+$/, "", v); sub(/\.$/, "", v) }
+            if (v == "-0") v = "0"
+            out = out substr(s, 1, RSTART + 1) v
+            s = substr(s, RSTART + RLENGTH)
+        }
+        print out s
+    }'
+}
+
+# Shared awk helpers for reading JSONL samples and RFC3339 timestamps.
+# Prepend to an awk program: awk "$AWK_LIB"'{ ... }'
+AWK_LIB='
+function iso_to_epoch(ts,    y, mo, d, s, rest, off, era, yoe, doy, doe) {
+    if (ts !~ /^[0-9][0-9][0-9][0-9]-[0-9][0-9]-[0-9][0-9]T[0-9][0-9]:[0-9][0-9]:[0-9][0-9]/) return ""
+    y = substr(ts, 1, 4) + 0; mo = substr(ts, 6, 2) + 0; d = substr(ts, 9, 2) + 0
+    s = substr(ts, 12, 2) * 3600 + substr(ts, 15, 2) * 60 + substr(ts, 18, 2)
+    rest = substr(ts, 20)
+    if (match(rest, /^\.[0-9]+/)) {
+        s += ("0" substr(rest, 1, RLENGTH)) + 0
+        rest = substr(rest, RLENGTH + 1)
+    }
+    off = 0
+    if (rest ~ /^[-+][0-9][0-9]:?[0-9][0-9]$/) {
+        off = substr(rest, 2, 2) * 3600 + substr(rest, length(rest) - 1, 2) * 60
+        if (substr(rest, 1, 1) == "+") off = -off
+    }
+    # Days since epoch (proleptic Gregorian calendar)
+    if (mo <= 2) y--
+    era = int((y >= 0 ? y : y - 399) / 400)
+    yoe = y - era * 400
+    doy = int((153 * (mo + (mo > 2 ? -3 : 9)) + 2) / 5) + d - 1
+    doe = yoe * 365 + int(yoe / 4) - int(yoe / 100) + doy
+    return (era * 146097 + doe - 719468) * 86400 + s + off
+}
+function epoch_to_iso(t,    days, s, era, doe, yoe, doy, mp, d, m, y) {
+    days = int(t / 86400)
+    if (days * 86400 > t) days--
+    s = t - days * 86400
+    # Civil date from days since epoch, the inverse of the above
+    days += 719468
+    era = int((days >= 0 ? days : days - 146096) / 146097)
+    doe = days - era * 146097
+    yoe = int((doe - int(doe / 1460) + int(doe / 36524) - int(doe / 146096)) / 365)
+    y = yoe + era * 400
+    doy = doe - (365 * yoe + int(yoe / 4) - int(yoe / 100))
+    mp = int((5 * doy + 2) / 153)
+    d = doy - int((153 * mp + 2) / 5) + 1
+    m = mp + (mp < 10 ? 3 : -9)
+    if (m <= 2) y++
+    return sprintf("%04d-%02d-%02dT%02d:%02d:%02dZ", y, m, d, int(s / 3600), int(s % 3600 / 60), int(s % 60))
+}
+function json_num(line, key,    v) {
+    if (!match(line, "\"" key "\"[ \t]*:[ \t]*")) return ""
+    v = substr(line, RSTART + RLENGTH)
+    if (!match(v, /^-?[0-9]+(\.[0-9]+)?([eE][-+]?[0-9]+)?/)) return ""
+    return substr(v, 1, RLENGTH)
+}
+function json_str(line, key,    v) {
+    if (!match(line, "\"" key "\"[ \t]*:[ \t]*\"")) return ""
+    v = substr(line, RSTART + RLENGTH)
+    if (!match(v, /^([^"\\]|\\.)*/)) return ""
+    v = substr(v, 1, RLENGTH)
+    gsub(/\\"/, "\"", v)
+    gsub(/\\\\/, "\\", v)
+    return v
+}
+function json_quote(s) {
+    gsub(/\\/, "\\\\\\\\", s)
+    gsub(/"/, "\\\"", s)
+    gsub(/\t/, "\\t", s)
+    gsub(/\n/, "\\n", s)
+    return "\"" s "\""
+}
+function json_number(v) {
+    # Keep the digits as written; v + 0 would round to six significant digits
+    return (v ~ /^-?[0-9]+(\.[0-9]+)?([eE][-+]?[0-9]+)?$/) ? v : v + 0
+}
+function heapsort(A, left, right,    n, i, t, end) {
+    # No recursion, so long runs of equal values cannot exhaust
+    # the awk stack the way a naive quicksort does
+    n = right - left + 1
+    if (n < 2) return
+    for (i = int(n / 2); i >= 1; i--) sift_down(A, left - 1, i, n)
+    for (end = n; end > 1; end--) {
+        t = A[left]; A[left] = A[left + end - 1]; A[left + end - 1] = t
+        sift_down(A, left - 1, 1, end - 1)
+    }
+}
+function sift_down(A, off, i, n,    c, t) {
+    while ((c = 2 * i) <= n) {
+        if (c < n && A[off + c + 1] > A[off + c]) c++
+        if (A[off + i] >= A[off + c]) return
+        t = A[off + i]; A[off + i] = A[off + c]; A[off + c] = t
+        i = c
+    }
+}
+function median(A, n) {
+    if (n == 0) return ""
+    heapsort(A, 1, n)
+    return (n % 2) ? A[(n + 1) / 2] : (A[n / 2] + A[n / 2 + 1]) / 2
+}
+function trimmed_mean(A, n, pct,    k, i, sum) {
+    if (n == 0) return ""
+    heapsort(A, 1, n)
+    k = int(n * pct / 100)
+    if (2 * k >= n) return median(A, n)
+    for (i = k + 1; i <= n - k; i++) sum += A[i]
+    return sum / (n - 2 * k)
+}
+function quantile(A, n, p,    h, i) {
+    # Linear interpolation between order statistics; A must be sorted
+    h = 1 + (n - 1) * p
+    i = int(h)
+    return (i >= n) ? A[n] : A[i] + (h - i) * (A[i + 1] - A[i])
+}
+function outlier_fences(A, n, method,    i, m, ss, sd, q1, q3) {
+    # Sets FENCE_LO and FENCE_HI for the n values in A (sorted in place):
+    # Tukey fences 1.5 IQR beyond the quartiles (iqr) or 3 standard
+    # deviations from the mean (zscore). Fewer than 4 values have no
+    # outliers and get empty fences.
+    FENCE_LO = FENCE_HI = ""
+    if (n < 4) return
+    if (method == "iqr") {
+        heapsort(A, 1, n)
+        q1 = quantile(A, n, 0.25); q3 = quantile(A, n, 0.75)
+        FENCE_LO = q1 - 1.5 * (q3 - q1); FENCE_HI = q3 + 1.5 * (q3 - q1)
+    } else if (method == "zscore") {
+        for (i = 1; i <= n; i++) m += A[i]
+        m /= n
+        for (i = 1; i <= n; i++) ss += (A[i] - m) ^ 2
+        sd = sqrt(ss / (n - 1))
+        FENCE_LO = m - 3 * sd; FENCE_HI = m + 3 * sd
+    }
+}
+function log_gamma(x,    t, ser) {
+    # Lanczos approximation, accurate to about 1e-10 for x > 0
+    t = x + 5.5
+    t -= (x + 0.5) * log(t)
+    ser = 1.000000000190015 + 76.18009172947146 / (x + 1) - 86.50532032941677 / (x + 2) \
+        + 24.01409824083091 / (x + 3) - 1.231739572450155 / (x + 4) \
+        + 0.1208650973866179e-2 / (x + 5) - 0.5395239384953e-5 / (x + 6)
+    return -t + log(2.5066282746310005 * ser / x)
+}
+function beta_cf(a, b, x,    m, m2, aa, c, d, del, h) {
+    # Continued fraction for the incomplete beta function (Lentz)
+    c = 1; d = 1 - (a + b) * x / (a + 1)
+    if (d < 1e-30 && d > -1e-30) d = 1e-30
+    d = 1 / d; h = d
+    for (m = 1; m <= 200; m++) {
+        m2 = 2 * m
+        aa = m * (b - m) * x / ((a + m2 - 1) * (a + m2))
+        d = 1 + aa * d; if (d < 1e-30 && d > -1e-30) d = 1e-30
+        c = 1 + aa / c; if (c < 1e-30 && c > -1e-30) c = 1e-30
+        d = 1 / d; h *= d * c
+        aa = -(a + m) * (a + b + m) * x / ((a + m2) * (a + m2 + 1))
+        d = 1 + aa * d; if (d < 1e-30 && d > -1e-30) d = 1e-30
+        c = 1 + aa / c; if (c < 1e-30 && c > -1e-30) c = 1e-30
+        d = 1 / d; del = d * c; h *= del
+        if (del > 1 - 3e-12 && del < 1 + 3e-12) break
+    }
+    return h
+}
+function inc_beta(a, b, x,    bt) {
+    if (x <= 0) return 0
+    if (x >= 1) return 1
+    bt = exp(log_gamma(a + b) - log_gamma(a) - log_gamma(b) + a * log(x) + b * log(1 - x))
+    if (x < (a + 1) / (a + b + 2)) return bt * beta_cf(a, b, x) / a
+    return 1 - bt * beta_cf(b, a, 1 - x) / b
+}
+function t_pvalue(t, df) {
+    # Two-sided p-value of Student t with df degrees of freedom
+    return inc_beta(df / 2, 0.5, df / (df + t * t))
+}
+'
+
+# Duration helpers: "90", "90s", "30m", "2h", "1h30m" <-> seconds
+parse_duration() {
+    printf '%s\n' "$1" | awk '
+    {
+        s = $0; total = 0
+        if (s ~ /^[0-9]+(\.[0-9]+)?$/) { print s + 0; exit 0 }
+        if (s !~ /^([0-9]+(\.[0-9]+)?[dhms])+$/) exit 1
+        while (match(s, /^[0-9]+(\.[0-9]+)?[dhms]/)) {
+            n = substr(s, 1, RLENGTH - 1) + 0
+            u = substr(s, RLENGTH, 1)
+            total += n * (u == "d" ? 86400 : u == "h" ? 3600 : u == "m" ? 60 : 1)
+            s = substr(s, RLENGTH + 1)
+        }
+        print total
+    }'
+}
+
+format_duration() {
+    printf '%s\n' "$1" | awk '{
+        s = int($1 + 0.5); h = int(s / 3600); m = int((s % 3600) / 60); s = s % 60
+        if (h > 0) printf "%dh%02dm\n", h, m
+        else if (m > 0) printf "%dm%02ds\n", m, s
+        else printf "%ds\n", s
+    }'
+}
+
+# Normalise a JSONL telemetry file into tab-separated rows:
+#   epoch  pct  watts  cpu_load  ram_pct  temp_c  src  io_kb  meter_w  state
+#   backlight_pct
+# Missing or malformed values are left empty. io_kb is a cumulative counter;
+# filters that aggregate samples (resample, gap filling) drop it, meter_w,
+# state and backlight_pct.
+run_samples() {
+    awk "$AWK_LIB"'
+    {
+        t = iso_to_epoch(json_str($0, "t"))
+        if (t == "") next
+        printf "%.3f\t%s\t%s\t%s\t%s\t%s\t%s\t%s\t%s\t%s\t%s\n", t, json_num($0, "pct"), json_num($0, "watts"),
+            json_num($0, "cpu_load"), json_num($0, "ram_pct"), json_num($0, "temp_c"), json_str($0, "src"),
+            json_num($0, "io_kb"), json_num($0, "meter_w"), json_str($0, "state"), json_num($0, "backlight_pct")
+    }' "$1"
+}
+
+# Keep only samples within the first WINDOW seconds of a run, i.e. [0, WINDOW)
+window_samples() {
+    awk -F'\t' -v win="$1" 'NR == 1 { start = $1 } $1 - start < win'
+}
+
+# Resample a sample stream onto a fixed grid of STEP seconds anchored at the
+# run start. Each bucket becomes one row holding the mean of every numeric
+# column; empty buckets are skipped and src is the last value seen.
+resample_samples() {
+    awk -F'\t' -v step="$1" '
+    function flush(    i, out) {
+        if (n == 0) return
+        out = sprintf("%.3f", start + bucket * step)
+        for (i = 2; i <= 6; i++) out = out "\t" (cnt[i] ? sum[i] / cnt[i] : "")
+        print out "\t" src
+        n = 0
+        for (i = 2; i <= 6; i++) { sum[i] = 0; cnt[i] = 0 }
+    }
+    BEGIN { OFS = "\t"; bucket = -1 }
+    {
+        if (NR == 1) start = $1
+        b = int(($1 - start) / step)
+        if (b != bucket) { flush(); bucket = b }
+        for (i = 2; i <= 6; i++) if ($i != "") { sum[i] += $i; cnt[i]++ }
+        src = $7
+        n++
+    }
+    END { flush() }'
+}
+
+# Sample gaps (suspends, hangs). An interval longer than THRESHOLD seconds,
+# or five times the median sample interval when THRESHOLD is empty, is a gap.
+# Prints: threshold  gaps  gap_seconds  median_interval
+gap_stats() {
+    awk -F'\t' -v limit="$1" "$AWK_LIB"'
+    { t[NR] = $1; if (NR > 1) dt[NR - 1] = $1 - t[NR - 1] }
+    END {
+        n = NR - 1
+        for (i = 1; i <= n; i++) sorted[i] = dt[i]
+        med = (n > 0) ? median(sorted, n) : 0
+        thr = (limit != "") ? limit : 5 * med
+        for (i = 1; i <= n; i++) if (thr > 0 && dt[i] > thr) { gaps++; total += dt[i] }
+        printf "%.3f\t%d\t%.0f\t%.3f\n", thr, gaps, total, med
+    }'
+}
+
+# Fill gaps longer than THRESHOLD seconds with synthetic samples every STEP
+# seconds, either linearly interpolated or carrying the last sample forward
+# (MODE interpolate or ffill). Synthetic samples have src "estimate".
+fill_gaps() {
+    awk -F'\t' -v mode="$1" -v thr="$2" -v step="$3" '
+    BEGIN { OFS = "\t" }
+    NR > 1 && thr > 0 && step > 0 && $1 - pt > thr {
+        for (t = pt + step; t < $1 - step / 2; t += step) {
+            f = (t - pt) / ($1 - pt)
+            out = sprintf("%.3f", t)
+            for (i = 2; i <= 6; i++) {
+                if (mode == "ffill") v = prev[i]
+                else if (prev[i] == "" || $i == "") v = ""
+                else v = prev[i] + f * ($i - prev[i])
+                out = out "\t" v
+            }
+            print out, "estimate"
+        }
+    }
+    { print $1, $2, $3, $4, $5, $6, $7; pt = $1; for (i = 2; i <= 6; i++) prev[i] = $i }'
+}
+
+# Summarise a sample stream (see run_samples) into one tab-separated record:
+#   samples  duration_s  start_pct  end_pct  avg_w  med_w  avg_cpu  avg_temp
+summarize_samples() {
+    awk -F'\t' "$AWK_LIB"'
+    {
+        if (NR == 1) start = $1
+        last = $1
+        n++
+        if ($2 != "") { if (start_pct == "") start_pct = $2; end_pct = $2 }
+        if ($3 != "") { nw++; sum_w += $3; W[nw] = $3 + 0 }
+        if ($4 != "") { nc++; sum_c += $4 * 100 }
+        if ($6 != "") { nt++; sum_t += $6 }
+    }
+    END {
+        printf "%d\t%.0f\t%s\t%s\t%s\t%s\t%s\t%s\n", n, (n ? last - start : 0), start_pct, end_pct,
+            (nw ? sprintf("%.2f", sum_w / nw) : ""), (nw ? sprintf("%.2f", median(W, nw)) : ""),
+            (nc ? sprintf("%.1f", sum_c / nc) : ""), (nt ? sprintf("%.1f", sum_t / nt) : "")
+    }'
+}
+
+# Count samples per source in a sample stream, in order of first use:
+#   upower:1200 sysfs:14
+# More than one entry means the collector fell back mid-run.
+source_counts() {
+    awk -F'\t' '
+    { s = ($7 == "") ? "none" : $7; if (!(s in n)) order[++k] = s; n[s]++ }
+    END { for (i = 1; i <= k; i++) printf "%s%s:%d", (i > 1 ? " " : ""), order[i], n[order[i]]; printf "\n" }'
+}
+
+# Battery drain of a sample stream in percent per hour: the falls between
+# consecutive percentage readings over the time they took. Rises are left
+# out rather than netted against the drain. Empty with less than five
+# minutes of readings, where a single percent step would dominate.
+drain_rate() {
+    awk -F'\t' '
+    $2 == "" { next }
+    {
+        if (prev != "" && $2 <= prev) { fall += prev - $2; dt += $1 - t }
+        prev = $2; t = $1
+    }
+    END { if (dt >= 300) printf "%.2f\n", fall / dt * 3600; else print "" }'
+}
+
+# Energy drawn over a sample stream, by trapezoidal integration of the
+# watts over the sample timestamps, and its rate:
+#   energy_wh  wh_per_hour
+# Unlike avg_w this weighs each reading by the time it covers. Intervals
+# longer than GAP seconds (if given and positive) are holes and add neither
+# energy nor time. Both are empty without two consecutive power readings.
+energy_integral() {
+    awk -F'\t' -v gap="$1" '
+    {
+        if (prev_w != "" && $3 != "" && (gap + 0 <= 0 || $1 - prev_t <= gap)) {
+            energy += (prev_w + $3) / 2 * ($1 - prev_t)
+            dt += $1 - prev_t
+        }
+        prev_t = $1
+        prev_w = $3
+    }
+    END { if (dt > 0) printf "%.4f\t%.2f\n", energy / 3600, energy / dt; else printf "\t\n" }'
+}
+
+# Robust drain fit for battery-life projection: the Theil-Sen slope of pct
+# over time, the median of the slopes between every pair of readings of the
+# same run, with Sen's 95% interval from the ranks of those slopes. Runs are
+# fitted together but never paired with each other, so runs of a config
+# pool without their start levels mattering. Reads "run epoch pct" lines;
+# each run is thinned to at most MAX_POINTS readings to bound the pairs.
+# Prints
+#   pairs  pct_per_h  pct_per_h_low  pct_per_h_high
+# with the rates as positive drain; exits 1 without two readings in a run.
+#   drain_fit [MAX_POINTS]
+drain_fit() {
+    awk -F'\t' -v max="${1:-150}" "$AWK_LIB"'
+    function rate(slope) { slope = -slope * 3600; return (slope == 0) ? 0 : slope }
+    $3 != "" {
+        if (!($1 in n)) order[++runs] = $1
+        n[$1]++; T[$1, n[$1]] = $2; P[$1, n[$1]] = $3
+    }
+    END {
+        for (r = 1; r <= runs; r++) {
+            id = order[r]
+            step = (n[id] > max) ? n[id] / max : 1
+            m = 0
+            for (x = 1; x <= n[id]; x += step) { i = int(x); m++; X[m] = T[id, i]; Y[m] = P[id, i] }
+            for (i = 1; i < m; i++)
+                for (j = i + 1; j <= m; j++)
+                    if (X[j] > X[i]) S[++k] = (Y[j] - Y[i]) / (X[j] - X[i])
+            # Variance of the Kendall statistic, summed over runs
+            var += m * (m - 1) * (2 * m + 5) / 18
+        }
+        if (!k) exit 1
+        heapsort(S, 1, k)
+        c = 1.96 * sqrt(var)
+        lo = int((k - c) / 2); hi = int((k + c) / 2) + 1
+        if (lo < 1) lo = 1
+        if (hi > k) hi = k
+        printf "%d\t%.3f\t%.3f\t%.3f\n", k, rate(median(S, k)), rate(S[hi]), rate(S[lo])
+    }'
+}
+
+# Samples whose watts are outliers within the stream (startup spikes and
+# the like) by METHOD, iqr or zscore (see outlier_fences). With "count"
+# prints their number, with "drop" the other rows; rows without watts are
+# kept.
+#   watt_outliers METHOD count|drop
+watt_outliers() {
+    awk -F'\t' -v method="$1" -v mode="$2" "$AWK_LIB"'
+    { row[NR] = $0; w[NR] = $3 }
+    $3 != "" { A[++n] = $3 + 0 }
+    END {
+        outlier_fences(A, n, method)
+        for (i = 1; i <= NR; i++) {
+            if (FENCE_LO != "" && w[i] != "" && (w[i] + 0 < FENCE_LO || w[i] + 0 > FENCE_HI)) k++
+            else if (mode == "drop") print row[i]
+        }
+        if (mode == "count") print k + 0
+    }'
+}
+
+# Battery percentage should only fall during a discharge run; where it
+# rises (charging intervals, sensor glitches) the samples from the first
+# rise up to the next fall form a segment. Reads a sample stream; with
+# "count" prints "segments<TAB>samples", with "drop" the rows outside them.
+pct_rises() {
+    awk -F'\t' -v mode="$1" '
+    $2 == "" { if (mode == "drop") print; next }
+    {
+        if (prev != "" && ($2 > prev || (in_seg && $2 >= prev))) {
+            if (!in_seg) { segments++; in_seg = 1 }
+            samples++
+            prev = $2
+            next
+        }
+        in_seg = 0
+        prev = $2
+        if (mode == "drop") print
+    }
+    END { if (mode == "count") printf "%d\t%d\n", segments, samples }'
+}
+
+# Read a top-level string field from a metadata file
+meta_field() {
+    [ -f "$1" ] || return 0
+    awk -v key="$2" "$AWK_LIB"'{ v = json_str($0, key); if (v != "") { print v; exit } }' "$1"
+}
+
+# Read a top-level numeric field from a metadata file
+meta_number() {
+    [ -f "$1" ] || return 0
+    awk -v key="$2" "$AWK_LIB"'{ v = json_num($0, key); if (v != "") { print v; exit } }' "$1"
+}
+
+# CPU topology of a run as "4c/8t smt on", or nothing for runs recorded
+# before topology was stored
+run_topology() {
+    local cores=$(meta_number "$1" cpu_cores)
+    local threads=$(meta_number "$1" cpu_threads)
+    local smt=$(meta_field "$1" smt)
+    [ -n "$cores$threads$smt" ] || return 0
+    printf '%sc/%st smt %s' "${cores:-?}" "${threads:-?}" "${smt:-?}"
+}
+
+# Device power states recorded with a run as "device<TAB>state" lines;
+# nothing for runs recorded before they were
+run_device_power() {
+    [ -f "$1" ] || return 0
+    awk '
+    match($0, /"device_power"[ \t]*:[ \t]*\{/) {
+        v = substr($0, RSTART + RLENGTH)
+        while (match(v, /^[ \t,]*"[^"]*"[ \t]*:[ \t]*"[^"]*"/)) {
+            pair = substr(v, RSTART, RLENGTH)
+            v = substr(v, RSTART + RLENGTH)
+            split(pair, q, "\"")
+            printf "%s\t%s\n", q[2], q[4]
+        }
+        exit
+    }' "$1"
+}
+
+# Configuration file (batlab.toml)
+#
+# A small TOML subset: [section] headers and key = value lines, where values
+# are quoted strings, numbers or booleans. Comments start with #.
+CONFIG_AWK='
+function config_value(v) {
+    sub(/^[ \t]+/, "", v)
+    if (v ~ /^"/) {
+        v = substr(v, 2)
+        match(v, /^([^"\\]|\\.)*/)
+        v = substr(v, 1, RLENGTH)
+        gsub(/\\"/, "\"", v)
+        return v
+    }
+    sub(/[ \t]*#.*$/, "", v)
+    sub(/[ \t]+$/, "", v)
+    return v
+}
+/^[ \t]*(#|$)/ { next }
+/^[ \t]*\[/ {
+    section = $0
+    gsub(/^[ \t]*\[[ \t]*|[ \t]*\][ \t]*(#.*)?$/, "", section)
+    next
+}
+match($0, /^[ \t]*[A-Za-z0-9_.-]+[ \t]*=/) {
+    key = substr($0, 1, RLENGTH - 1)
+    gsub(/[ \t]/, "", key)
+    value = config_value(substr($0, RLENGTH + 1))
+}
+'
+
+# Print the value of KEY in SECTION ("" for top level), or nothing
+config_get() {
+    [ -f "$CONFIG_FILE" ] || return 0
+    awk -v want_section="$1" -v want_key="$2" "$CONFIG_AWK"'
+    key != "" && section == want_section && key == want_key { print value; exit }
+    { key = "" }' "$CONFIG_FILE"
+}
+
+# Print "name<TAB>key<TAB>value" for every key in sections named PREFIX.<name>
+config_entries() {
+    [ -f "$CONFIG_FILE" ] || return 0
+    awk -v prefix="$1." "$CONFIG_AWK"'
+    key != "" && index(section, prefix) == 1 { printf "%s\t%s\t%s\n", substr(section, length(prefix) + 1), key, value }
+    { key = "" }' "$CONFIG_FILE"
+}
+
+# Set KEY in SECTION to VALUE (a TOML literal, e.g. a quoted string),
+# replacing an existing key or creating the section and file as needed
+config_set() {
+    local tmp="${CONFIG_FILE}.tmp.$$"
+    { [ -f "$CONFIG_FILE" ] && cat "$CONFIG_FILE"; true; } | awk -v want_section="$1" -v want_key="$2" -v val="$3" '
+    function emit() { print want_key " = " val; done = 1 }
+    /^[ \t]*$/ { pending = pending $0 "\n"; next }
+    /^[ \t]*\[/ {
+        if (in_section && !done) emit()
+        printf "%s", pending; pending = ""
+        s = $0
+        gsub(/^[ \t]*\[[ \t]*|[ \t]*\][ \t]*(#.*)?$/, "", s)
+        in_section = (s == want_section)
+        print
+        next
+    }
+    in_section && match($0, /^[ \t]*[A-Za-z0-9_.-]+[ \t]*=/) {
+        k = substr($0, 1, RLENGTH - 1)
+        gsub(/[ \t]/, "", k)
+        if (k == want_key) {
+            printf "%s", pending; pending = ""
+            if (!done) emit()
+            next
+        }
+    }
+    { printf "%s", pending; pending = ""; print }
+    END {
+        if (in_section && !done) emit()
+        printf "%s", pending
+        if (!done) { if (NR) print ""; print "[" want_section "]"; emit() }
+    }' > "$tmp" && mv "$tmp" "$CONFIG_FILE"
+}
+
+# Print KEY from the [HOST] section of the host registry (hosts.toml)
+host_get() {
+    [ -f "$HOSTS_FILE" ] || return 0
+    awk -v want_section="$1" -v want_key="$2" "$CONFIG_AWK"'
+    key != "" && section == want_section && key == want_key { print value; exit }
+    { key = "" }' "$HOSTS_FILE"
+}
+
+# Print the host names registered in the host registry, in file order
+host_names() {
+    [ -f "$HOSTS_FILE" ] || return 0
+    awk '/^[ \t]*\[/ {
+        s = $0
+        gsub(/^[ \t]*\[[ \t]*|[ \t]*\][ \t]*(#.*)?$/, "", s)
+        print s
+    }' "$HOSTS_FILE"
+}
+
+toml_quote() {
+    printf '"%s"' "$(json_escape "$1")"
+}
+
+# Print PID and the pids of all its descendants
+process_tree() {
+    ps -A -o pid= -o ppid= 2>/dev/null | awk -v root="$1" '
+    { parent[$1] = $2; pids[++n] = $1 }
+    END {
+        mark[root] = 1
+        do {
+            changed = 0
+            for (i = 1; i <= n; i++)
+                if (!(pids[i] in mark) && (parent[pids[i]] in mark)) { mark[pids[i]] = 1; changed = 1 }
+        } while (changed)
+        print root
+        for (i = 1; i <= n; i++) if (pids[i] in mark && pids[i] != root) print pids[i]
+    }'
+}
+
+# Run a command, killing it after a timeout in seconds: with timeout(1)
+# where it exists, else with a background watcher that kills the command
+# and everything it started, as timeout(1) does. Its stdin is /dev/null
+# without timeout(1).
+run_with_timeout() {
+    local seconds="$1"
+    shift
+    if command -v timeout >/dev/null 2>&1; then
+        timeout "$seconds" "$@"
+        return
+    fi
+    "$@" &
+    local pid=$!
+    # The watcher must not hold the caller's output open, or a command
+    # substitution around us would wait for it
+    (
+        trap 'kill "$sleeper" 2>/dev/null; exit 0' TERM
+        sleep "$seconds" &
+        sleeper=$!
+        wait "$sleeper"
+        kill $(process_tree "$pid") 2>/dev/null
+    ) > /dev/null 2>&1 &
+    local watcher=$!
+    local status=0
+    wait "$pid" || status=$?
+    kill "$watcher" 2>/dev/null
+    wait "$watcher" 2>/dev/null
+    return "$status"
+}
+
+# External readings
+#
+# Collectors, probes and plugins (below) read values from outside batlab
+# each sample. They share one path: run_external runs each with its timeout
+# and warns when it fails, and json_members merges what they read into the
+# members of their object in the sample.
+
+# Run COMMAND for the KIND (Collector, Probe, Plugin) NAME, killed after
+# TIMEOUT seconds, and print its output; fails with a warning when it
+# fails or times out
+#   run_external KIND NAME TIMEOUT COMMAND [ARG...]
+run_external() {
+    local kind="$1" name="$2" timeout="$3" output
+    shift 3
+    if ! output=$(run_with_timeout "$timeout" "$@" 2>/dev/null); then
+        log_warn "$kind $name failed or timed out after ${timeout}s"
+        return 1
+    fi
+    printf '%s\n' "$output"
+}
+
+# Join "NAME<TAB>JSON" lines into JSON members: "NAME": JSON, ...
+json_members() {
+    awk -F'\t' "$AWK_LIB"'
+    NF >= 2 {
+        name = $1
+        value = substr($0, length(name) + 2)
+        printf "%s%s: %s", (n++ ? ", " : ""), json_quote(name), value
+    }'
+}
+
+# External collectors
+#
+# [collectors.<name>] sections declare executables that print name=value
+# lines. Their output is merged into each sample under "extra". A collector
+# that fails, times out (timeout, default 2 seconds) or prints nothing
+# usable is skipped for that sample.
+COLLECTORS_LOADED=""
+COLLECTORS=""
+
+load_collectors() {
+    [ -n "$COLLECTORS_LOADED" ] && return 0
+    COLLECTORS_LOADED=1
+    COLLECTORS=$(config_entries collectors | awk -F'\t' '
+        !($1 in seen) { seen[$1] = 1; order[++n] = $1; timeout[$1] = 2 }
+        $2 == "command" { command[$1] = $3 }
+        $2 == "timeout" { timeout[$1] = $3 }
+        END { for (i = 1; i <= n; i++) if (command[order[i]] != "") printf "%s\t%s\t%s\n", order[i], timeout[order[i]], command[order[i]] }')
+}
+
+# Print the JSON members ("key": value, ...) gathered from all collectors
+run_collectors() {
+    load_collectors
+    [ -n "$COLLECTORS" ] || return 0
+
+    printf '%s\n' "$COLLECTORS" | while IFS="$(printf '\t')" read -r name timeout command; do
+        run_external Collector "$name" "$timeout" sh -c "$command"
+    done | awk "$AWK_LIB"'
+    match($0, /^[ \t]*[A-Za-z0-9_.-]+[ \t]*=/) {
+        key = substr($0, 1, RLENGTH - 1)
+        gsub(/[ \t]/, "", key)
+        value = substr($0, RLENGTH + 1)
+        gsub(/^[ \t]+|[ \t]+$/, "", value)
+        if (!(value ~ /^-?[0-9]+(\.[0-9]+)?([eE][-+]?[0-9]+)?$/)) value = json_quote(value)
+        printf "%s\t%s\n", key, value
+    }' | json_members
+}
+
+# Custom probes
+#
+# [probes.<name>] sections read one extra number per sample without a
+# script: a sysctl (sysctl = "NAME"), the first line of a file such as a
+# sysfs attribute (file = "PATH") or the output of a command (command =
+# "CMD", killed after timeout seconds, default 2). The leading number read
+# is multiplied by scale (default 1) and merged into the sample under
+# "custom"; a probe that cannot be read is null for that sample.
+PROBES_LOADED=""
+PROBES=""
+
+load_probes() {
+    [ -n "$PROBES_LOADED" ] && return 0
+    PROBES_LOADED=1
+    PROBES=$(config_entries probes | awk -F'\t' '
+        !($1 in seen) { seen[$1] = 1; order[++n] = $1; scale[$1] = 1; timeout[$1] = 2 }
+        $2 == "sysctl" || $2 == "file" || $2 == "command" { kind[$1] = $2; target[$1] = $3 }
+        $2 == "scale" { scale[$1] = $3 }
+        $2 == "timeout" { timeout[$1] = $3 }
+        END {
+            for (i = 1; i <= n; i++) {
+                p = order[i]
+                if (kind[p] != "") printf "%s\t%s\t%s\t%s\t%s\n", p, kind[p], scale[p], timeout[p], target[p]
+            }
+        }')
+}
+
+# The sysctl names the probes read, for the per-sample sysctl snapshot
+probe_sysctls() {
+    printf '%s\n' "$PROBES" | awk -F'\t' '$2 == "sysctl" { print $5 }'
+}
+
+# Print the JSON members ("name": value, ...) read by all probes
+run_probes() {
+    load_probes
+    [ -n "$PROBES" ] || return 0
+
+    printf '%s\n' "$PROBES" | while IFS="$(printf '\t')" read -r name kind scale timeout target; do
+        local value=""
+        case "$kind" in
+            sysctl) value=$(sysctl_get "$target" | head -n 1) ;;
+            file) value=$(head -n 1 "$target" 2>/dev/null) ;;
+            command) value=$(run_external Probe "$name" "$timeout" sh -c "$target" | head -n 1) ;;
+        esac
+        printf '%s\t%s\t%s\n' "$name" "$scale" "$value"
+    done | awk -F'\t' "$AWK_LIB"'
+    {
+        v = $3
+        sub(/^[ \t]+/, "", v)
+        if (!match(v, /^-?[0-9]+(\.[0-9]+)?([eE][-+]?[0-9]+)?/)) v = "null"
+        else if ($2 == 1) v = json_number(substr(v, 1, RLENGTH))
+        else v = json_number(sprintf("%.10g", substr(v, 1, RLENGTH) * $2))
+        printf "%s\t%s\n", $1, v
+    }' | json_members
+}
+
+# Plugins
+#
+# Every executable in PLUGIN_DIR (plugins/, or dir in [plugins]) runs once
+# per sample, killed after timeout seconds (default 2, timeout in
+# [plugins]), and prints a JSON object on one line. The objects are merged
+# into the sample under "plugins", keyed by file name. A plugin that fails,
+# times out or prints anything else (see json_object_line) is skipped for
+# that sample with a warning, so its output never reaches the sample log.
+PLUGINS_LOADED=""
+PLUGINS=""
+PLUGIN_TIMEOUT=2
+
+load_plugins() {
+    [ -n "$PLUGINS_LOADED" ] && return 0
+    PLUGINS_LOADED=1
+    PLUGIN_DIR="$(config_get plugins dir)"
+    PLUGIN_DIR="${PLUGIN_DIR:-plugins}"
+    PLUGIN_TIMEOUT="$(config_get plugins timeout)"
+    PLUGIN_TIMEOUT="${PLUGIN_TIMEOUT:-2}"
+    PLUGINS=""
+    local plugin
+    for plugin in "$PLUGIN_DIR"/*; do
+        [ -f "$plugin" ] && [ -x "$plugin" ] || continue
+        PLUGINS="$PLUGINS$plugin
+"
+    done
+}
+
+# Print the JSON members ("name": {...}, ...) of the plugins' objects
+run_plugins() {
+    load_plugins
+    [ -n "$PLUGINS" ] || return 0
+
+    local plugin output
+    printf '%s' "$PLUGINS" | while IFS= read -r plugin; do
+        output=$(run_external Plugin "${plugin##*/}" "$PLUGIN_TIMEOUT" "$plugin") || continue
+        if ! output=$(printf '%s\n' "$output" | json_object_line); then
+            log_warn "Plugin ${plugin##*/} did not print a JSON object on one line"
+            continue
+        fi
+        printf '%s\t%s\n' "${plugin##*/}" "$output"
+    done | json_members
+}
+
+# Print the input as one compact JSON object line, or fail when it is not a
+# single JSON object. With jq any object passes; without it only flat
+# objects of "key": number or "key": "string" members are accepted.
+json_object_line() {
+    if command -v jq >/dev/null 2>&1; then
+        jq -cse 'if length == 1 and (.[0] | type) == "object" then .[0] else error("not one object") end' 2>/dev/null
+        return
+    fi
+    awk '
+    BEGIN {
+        str = "\"([^\"\\\\]|\\\\.)*\""
+        num = "-?(0|[1-9][0-9]*)(\\.[0-9]+)?([eE][-+]?[0-9]+)?"
+        member = "[ \t]*" str "[ \t]*:[ \t]*(" str "|" num ")[ \t]*"
+        flat = "^[ \t]*\\{(" member "(," member ")*|[ \t]*)\\}[ \t]*$"
+    }
+    NR > 1 || $0 !~ flat { bad = 1; exit }
+    { line = $0 }
+    END {
+        if (bad || NR != 1) exit 1
+        sub(/^[ \t]+/, "", line); sub(/[ \t]+$/, "", line)
+        print line
+    }'
+}
+
+# External power meter
+#
+# A [meter] section names a reference meter read once per sample and
+# recorded as meter_w, so battery-reported watts can be checked against
+# ground truth:
+#   type = "shelly"        Shelly Gen1 plug at url = "http://plug"
+#        | "shelly-rpc"    Shelly Plus/Pro (Gen2+) plug at url
+#        | "tasmota"       Tasmota plug at url
+#        | "serial"        meter printing one reading per line on device,
+#                          at baud if given
+#        | "command"       command printing watts (USB HID meters through
+#                          their vendor tools)
+# timeout (seconds, default 2) bounds each reading. Serial and command
+# output is a bare number per line (optionally followed by W), unless field
+# (whitespace-separated, from 1) or pattern (an extended regular expression
+# whose match holds the number) says where the watts are. In the sampler a
+# background poller (meter_start) reads the meter about once a second into
+# METER_DIR, so a slow meter never holds a sample up; samples take its
+# latest reading.
+METER_LOADED=""
+METER_TYPE=""
+METER_TARGET=""
+METER_TIMEOUT=2
+METER_FIELD=""
+METER_PATTERN=""
+METER_DIR=""
+METER_PID=""
+
+load_meter() {
+    [ -n "$METER_LOADED" ] && return 0
+    METER_LOADED=1
+    METER_TYPE=$(config_get meter type)
+    [ -n "$METER_TYPE" ] || return 0
+
+    case "$METER_TYPE" in
+        shelly|shelly-rpc|tasmota) METER_TARGET=$(config_get meter url) ;;
+        serial)                    METER_TARGET=$(config_get meter device) ;;
+        command)                   METER_TARGET=$(config_get meter command) ;;
+        *)
+            log_warn "Unknown meter type: $METER_TYPE (expected shelly, shelly-rpc, tasmota, serial or command)"
+            METER_TYPE=""
+            return 0
+            ;;
+    esac
+    if [ -z "$METER_TARGET" ]; then
+        log_warn "Meter $METER_TYPE needs a url, device or command in [meter]; not reading it"
+        METER_TYPE=""
+        return 0
+    fi
+    METER_TIMEOUT=$(config_get meter timeout)
+    METER_TIMEOUT="${METER_TIMEOUT:-2}"
+    METER_FIELD=$(config_get meter field)
+    METER_PATTERN=$(config_get meter pattern)
+    case "$METER_FIELD" in
+        ''|[1-9]|[1-9][0-9]) ;;
+        *)
+            log_warn "Invalid meter field: $METER_FIELD (expected a field number from 1); using the whole line"
+            METER_FIELD=""
+            ;;
+    esac
+
+    local baud=$(config_get meter baud)
+    if [ "$METER_TYPE" = "serial" ] && [ -n "$baud" ]; then
+        stty -F "$METER_TARGET" "$baud" raw 2>/dev/null || stty -f "$METER_TARGET" "$baud" raw 2>/dev/null || \
+            log_warn "Could not set $METER_TARGET to $baud baud"
+    fi
+}
+
+# Fetch URL to stdout within TIMEOUT seconds with whichever client exists
+http_get() {
+    if command -v curl >/dev/null 2>&1; then
+        curl -fsS -m "$2" "$1"
+    elif command -v fetch >/dev/null 2>&1; then
+        fetch -q -T "$2" -o - "$1"
+    elif command -v wget >/dev/null 2>&1; then
+        wget -q -T "$2" -O - "$1"
+    else
+        return 1
+    fi
+}
+
+# Read the meter now and print its reading in watts; fails when it could
+# not be read within METER_TIMEOUT
+meter_read_now() {
+    local url="${METER_TARGET%/}"
+    local output=""
+    local key=""
+    case "$METER_TYPE" in
+        shelly)     output=$(http_get "$url/meter/0" "$METER_TIMEOUT" 2>/dev/null) && key=power ;;
+        shelly-rpc) output=$(http_get "$url/rpc/Switch.GetStatus?id=0" "$METER_TIMEOUT" 2>/dev/null) && key=apower ;;
+        tasmota)    output=$(http_get "$url/cm?cmnd=Status%208" "$METER_TIMEOUT" 2>/dev/null) && key=Power ;;
+        serial)     output=$(run_with_timeout "$METER_TIMEOUT" sh -c 'head -n 1 < "$1"' sh "$METER_TARGET" 2>/dev/null) ;;
+        command)    output=$(run_with_timeout "$METER_TIMEOUT" sh -c "$METER_TARGET" 2>/dev/null) ;;
+    esac
+
+    printf '%s\n' "$output" | awk -v key="$key" -v field="$METER_FIELD" -v pattern="$METER_PATTERN" "$AWK_LIB"'
+        function num(s) { return match(s, /-?[0-9]+(\.[0-9]+)?/) ? substr(s, RSTART, RLENGTH) : "" }
+        key != "" { v = json_num($0, key); if (v != "") { print v; found = 1; exit } next }
+        {
+            v = ""
+            if (field != "") v = num($field)
+            else if (pattern != "") { if (match($0, pattern)) v = num(substr($0, RSTART, RLENGTH)) }
+            else if ($0 ~ /^[ \t]*-?[0-9]+(\.[0-9]+)?[ \t]*W?[ \t]*$/) v = num($0)
+            if (v != "") { print v; found = 1; exit }
+        }
+        END { exit !found }'
+}
+
+# Start polling the meter in the background for the sampler, once. The
+# poller ends with the process that started it.
+meter_start() {
+    load_meter
+    [ -n "$METER_TYPE" ] && [ -z "$METER_PID" ] || return 0
+    METER_DIR=$(mktemp -d "${TMPDIR:-/tmp}/batlab-meter.XXXXXX") || return 0
+    (
+        trap '' INT
+        while kill -0 $$ 2>/dev/null; do
+            if watts=$(meter_read_now); then
+                printf '%s %s\n' "$(date +%s)" "$watts" > "$METER_DIR/reading.tmp" &&
+                    mv "$METER_DIR/reading.tmp" "$METER_DIR/reading"
+            fi
+            sleep 1
+        done
+        rm -rf "$METER_DIR"
+    ) &
+    METER_PID=$!
+}
+
+# Print the meter's current reading in watts, or nothing when no meter is
+# configured or it could not be read: the poller's latest reading if it is
+# recent, else a reading taken now
+read_meter() {
+    load_meter
+    [ -n "$METER_TYPE" ] || return 0
+
+    local watts=""
+    if [ -n "$METER_PID" ] && [ -f "$METER_DIR/reading" ]; then
+        local at
+        read -r at watts < "$METER_DIR/reading"
+        [ $(($(date +%s) - at)) -le $((METER_TIMEOUT + 3)) ] || watts=""
+    elif [ -z "$METER_PID" ]; then
+        watts=$(meter_read_now)
+    fi
+    if [ -z "$watts" ]; then
+        log_warn "Could not read $METER_TYPE meter $METER_TARGET"
+        return 0
+    fi
+    printf '%s' "$watts"
+}
+
+# Sensor selection
+#
+# `batlab init` records which battery and temperature sensor to use in the
+# [sensors] section of batlab.toml, using names that survive reboots (power
+# supply name, hwmon chip name, thermal zone type) rather than enumeration
+# order. Every run resolves those names to concrete paths once at startup.
+#
+#   battery     = "BAT0"                   Linux power_supply name
+#               = "0"                      FreeBSD acpiconf unit
+#   temperature = "hwmon:coretemp/temp1_input"
+#               | "thermal:x86_pkg_temp"
+#               | "sysctl:dev.cpu.0.temperature"
+#               | "/path/to/millidegree/file"
+SENSORS_RESOLVED=""
+SENSOR_BATTERY=""
+SENSOR_TEMP=""
+
+detect_battery_sensor() {
+    case "$(detect_platform)" in
+        linux)
+            for supply in "$SYSFS"/class/power_supply/*; do
+                [ "$(cat "$supply/type" 2>/dev/null)" = "Battery" ] || continue
+                # Peripheral batteries (mice, headsets) report scope Device
+                [ "$(cat "$supply/scope" 2>/dev/null)" = "Device" ] && continue
+                basename "$supply"
+                return 0
+            done
+            ;;
+        freebsd)
+            local units=$(freebsd_battery_units)
+            case "$units" in
+                "")    return 1 ;;
+                *" "*) echo "all" ;;
+                *)     echo "$units" ;;
+            esac
+            ;;
+    esac
+}
+
+# ACPI battery units that are actual batteries. hw.acpi.battery.units gives
+# the count; each unit is probed with acpiconf -i and kept only if it is
+# present and reports a design capacity, which skips empty bays and
+# UPS-like devices that answer with no capacity.
+freebsd_battery_units() {
+    command -v acpiconf >/dev/null 2>&1 || return 0
+    local count=$(sysctl -n hw.acpi.battery.units 2>/dev/null || echo 4)
+    local unit=0
+    local units=""
+
+    while [ "$unit" -lt "$count" ]; do
+        if acpiconf -i "$unit" 2>/dev/null | awk -F':[ \t]*' '
+            $1 ~ /^Design capacity/ && $2 + 0 > 0 { cap = 1 }
+            $1 ~ /^State/ && $2 ~ /not present/ { absent = 1 }
+            END { exit !(cap && !absent) }'; then
+            units="${units}${units:+ }$unit"
+        fi
+        unit=$((unit + 1))
+    done
+    printf '%s' "$units"
+}
+
+detect_temperature_sensor() {
+    case "$(detect_platform)" in
+        linux)
+            local chip zone
+            for chip in "$SYSFS"/class/hwmon/hwmon*; do
+                case "$(cat "$chip/name" 2>/dev/null)" in
+                    coretemp|k10temp|zenpower)
+                        if [ -f "$chip/temp1_input" ]; then
+                            echo "hwmon:$(cat "$chip/name")/temp1_input"
+                            return 0
+                        fi
+                        ;;
+                esac
+            done
+            for preferred in x86_pkg_temp acpitz; do
+                for zone in "$SYSFS"/class/thermal/thermal_zone*; do
+                    if [ "$(cat "$zone/type" 2>/dev/null)" = "$preferred" ]; then
+                        echo "thermal:$preferred"
+                        return 0
+                    fi
+                done
+            done
+            if [ -f "$SYSFS/class/thermal/thermal_zone0/temp" ]; then
+                echo "$SYSFS/class/thermal/thermal_zone0/temp"
+            fi
+            ;;
+        freebsd)
+            if sysctl -n dev.cpu.0.temperature >/dev/null 2>&1; then
+                echo "sysctl:dev.cpu.0.temperature"
+            elif sysctl -n hw.acpi.thermal.tz0.temperature >/dev/null 2>&1; then
+                echo "sysctl:hw.acpi.thermal.tz0.temperature"
+            fi
+            ;;
+    esac
+}
+
+# Resolve a temperature selector to a file path or sysctl:NAME
+resolve_temperature_sensor() {
+    local selector="$1"
+    case "$selector" in
+        hwmon:*/*)
+            local name="${selector#hwmon:}"
+            local file="${name#*/}"
+            name="${name%%/*}"
+            for chip in "$SYSFS"/class/hwmon/hwmon*; do
+                if [ "$(cat "$chip/name" 2>/dev/null)" = "$name" ] && [ -f "$chip/$file" ]; then
+                    echo "$chip/$file"
+                    return 0
+                fi
+            done
+            return 1
+            ;;
+        thermal:*)
+            for zone in "$SYSFS"/class/thermal/thermal_zone*; do
+                if [ "$(cat "$zone/type" 2>/dev/null)" = "${selector#thermal:}" ]; then
+                    echo "$zone/temp"
+                    return 0
+                fi
+            done
+            return 1
+            ;;
+        *)
+            echo "$selector"
+            ;;
+    esac
+}
+
+# Load [sensors] from the config once per process
+# Metric selection: the readings each sample takes, all of METRICS unless
+# SAMPLE_METRICS (log --metrics, or metrics in [sensors]) names fewer.
+# Reading hwmon and some sysfs attributes wakes devices on some machines and
+# changes what is being measured. Core readings that are off (cpu, memory,
+# temp) are null in the sample, others are left out. The battery is always
+# read.
+METRICS="battery cpu memory temp io rapl wifi backlight electrical freq"
+SAMPLE_METRICS=""
+
+# Set SAMPLE_METRICS from a comma- or space-separated LIST of metric names
+select_metrics() {
+    local list=$(printf '%s' "$1" | tr ',' ' ')
+    local metric
+    for metric in $list; do
+        case " $METRICS " in
+            *" $metric "*) ;;
+            *)
+                log_error "Unknown metric: $metric (expected some of: $METRICS)"
+                return 1
+                ;;
+        esac
+    done
+    SAMPLE_METRICS="battery"
+    for metric in $list; do
+        case " $SAMPLE_METRICS " in
+            *" $metric "*) ;;
+            *) SAMPLE_METRICS="$SAMPLE_METRICS $metric" ;;
+        esac
+    done
+}
+
+metric_enabled() {
+    case " ${SAMPLE_METRICS:-$METRICS} " in
+        *" $1 "*) return 0 ;;
+    esac
+    return 1
+}
+
+resolve_sensors() {
+    [ -n "$SENSORS_RESOLVED" ] && return 0
+    SENSORS_RESOLVED=1
+
+    local metrics=$(config_get sensors metrics)
+    if [ -z "$SAMPLE_METRICS" ] && [ -n "$metrics" ]; then
+        select_metrics "$metrics" || log_warn "Ignoring metrics in [sensors]; sampling all metrics"
+    fi
+
+    # batlab.toml wins; the host registry holds defaults for the machine
+    local host=$(get_hostname)
+    TELEMETRY_PROVIDER="${TELEMETRY_PROVIDER:-$(config_get sensors provider)}"
+    if [ -n "$TELEMETRY_PROVIDER" ] && ! provider_has battery && ! provider_has memory && ! provider_has temperature; then
+        log_warn "Unknown telemetry provider: $(telemetry_provider)"
+    fi
+    BATTERY_SOURCES=$(config_get sensors battery_sources)
+    local source
+    for source in $BATTERY_SOURCES; do
+        provider_has "battery_$source" || log_warn "Unknown battery source for $(telemetry_provider): $source"
+    done
+    if provider_has init; then
+        provider_call init
+    fi
+    SENSOR_BATTERY=$(config_get sensors battery)
+    SENSOR_BATTERY="${SENSOR_BATTERY:-$(host_get "$host" battery_sensor)}"
+    if [ "$(detect_platform)" = "freebsd" ]; then
+        # "all" (or nothing configured) sums every discovered battery
+        case "$SENSOR_BATTERY" in
+            ""|all) BATTERY_UNITS=$(freebsd_battery_units) ;;
+            *)      BATTERY_UNITS="$SENSOR_BATTERY" ;;
+        esac
+        BATTERY_UNITS="${BATTERY_UNITS:-0}"
+    fi
+    if [ "$(detect_platform)" = "linux" ]; then
+        if command -v busctl >/dev/null 2>&1 || command -v upower >/dev/null 2>&1; then
+            UPOWER_DEVICE=$(upower_battery_path) || UPOWER_DEVICE=""
+        fi
+    fi
+    local selector=$(config_get sensors temperature)
+    selector="${selector:-$(host_get "$host" temperature_sensor)}"
+    if [ -n "$selector" ]; then
+        if ! SENSOR_TEMP=$(resolve_temperature_sensor "$selector"); then
+            log_warn "Configured temperature sensor not found: $selector"
+            SENSOR_TEMP=""
+        fi
+    fi
+}
+
+# FreeBSD readings taken with sysctl(8) come from one snapshot per sample
+# while logging: sysctl_snapshot runs sysctl once for every name a sample
+# needs and sysctl_get looks them up in it, instead of starting sysctl for
+# each reading. At high sampling rates those processes measurably inflated
+# the power being measured. Without a snapshot sysctl_get runs sysctl.
+SYSCTL_SNAPSHOT=""
+SYSCTL_SNAPSHOT_NAMES="hw.acpi.acline hw.acpi.battery.life hw.acpi.battery.rate hw.acpi.battery.state dev.cpu.0.temperature hw.acpi.thermal.tz0.temperature hw.acpi.video.lcd0.brightness vm.stats.vm.v_page_count vm.stats.vm.v_free_count vm.stats.vm.v_inactive_count vm.stats.vm.v_cache_count"
+
+# Take the snapshot for the next sample; call from the shell that samples
+sysctl_snapshot() {
+    SYSCTL_SNAPSHOT=""
+    [ "$(detect_platform)" = "freebsd" ] || return 0
+    local names="$SYSCTL_SNAPSHOT_NAMES"
+    case "$SENSOR_TEMP" in
+        sysctl:*) names="$names ${SENSOR_TEMP#sysctl:}" ;;
+    esac
+    names="$names $(probe_sysctls | tr '\n' ' ')"
+    # -i skips names this machine lacks; they then read as missing
+    SYSCTL_SNAPSHOT=$(sysctl -i $names 2>/dev/null)
+    SYSCTL_SNAPSHOT="${SYSCTL_SNAPSHOT:-(none)}"
+}
+
+# Print the value of sysctl NAME, failing when it is missing
+sysctl_get() {
+    if [ -z "$SYSCTL_SNAPSHOT" ]; then
+        sysctl -n "$1" 2>/dev/null
+        return
+    fi
+    local line IFS='
+'
+    for line in $SYSCTL_SNAPSHOT; do
+        case "$line" in
+            "$1: "*)
+                printf '%s\n' "${line#"$1: "}"
+                return 0
+                ;;
+        esac
+    done
+    return 1
+}
+
+# Battery information collection
+# acpiconf(8) for every battery unit. With several batteries, percentages
+# are weighted by last full capacity and present rates are summed.
+freebsd_battery_acpiconf() {
+    command -v acpiconf >/dev/null 2>&1 || return 1
+    local acpi_output=""
+    for unit in ${BATTERY_UNITS:-0}; do
+        acpi_output="${acpi_output}$(acpiconf -i "$unit" 2>/dev/null || true)
+--
+"
+    done
+
+    local reading=$(printf '%s' "$acpi_output" | awk -F':[ \t]*' '
+        function flush() {
+            if (pct != "") {
+                n++
+                w = (full > 0) ? full : 1
+                sum_pct += pct * w; sum_w += w
+                if (rate != "") watts += rate / 1000
+            }
+            pct = ""; rate = ""; full = 0
+        }
+        $0 == "--" { flush(); next }
+        $1 ~ /^Remaining capacity/ { pct = $2; sub(/%.*/, "", pct) }
+        $1 ~ /^Present rate/ && $2 ~ /^[0-9]/ { rate = $2 + 0 }
+        $1 ~ /^Last full capacity/ { full = $2 + 0 }
+        END { flush(); if (n) printf "%.0f,%s\n", sum_pct / sum_w, watts + 0 }')
+
+    [ -n "$reading" ] || return 1
+    printf "%s,acpiconf" "$reading"
+}
+
+# The hw.acpi.battery sysctls, summarising all batteries
+freebsd_battery_sysctl() {
+    local percentage rate
+    percentage=$(sysctl_get hw.acpi.battery.life) || percentage=""
+    rate=$(sysctl_get hw.acpi.battery.rate) || rate=""
+    printf "%s,%s,sysctl" "${percentage:-50}" "$(awk -v mw="${rate:-5000}" 'BEGIN { print mw / 1000 }')"
+}
+
+freebsd_battery() {
+    freebsd_battery_acpiconf || freebsd_battery_sysctl
+}
+
+openbsd_battery() {
+    local percentage="50"
+    local watts="5.0"
+    local source="apm"
+
+    if command -v apm >/dev/null 2>&1; then
+        percentage=$(apm -l 2>/dev/null || echo "50")
+        # OpenBSD apm doesn't always provide power draw
+        watts="5.0"
+    else
+        source="dummy"
+    fi
+
+    printf "%s,%s,%s" "$percentage" "$watts" "$source"
+}
+
+netbsd_battery() {
+    local percentage="50"
+    local watts="5.0"
+    local source="envstat"
+
+    if command -v envstat >/dev/null 2>&1; then
+        local envstat_output
+        envstat_output=$(envstat 2>/dev/null || true)
+
+        if [ -n "$envstat_output" ]; then
+            percentage=$(echo "$envstat_output" | grep -i "charge" | head -1 | awk '{print $2}' | tr -d '%' || echo "50")
+            watts="5.0"  # NetBSD envstat may not provide power draw
+        else
+            source="dummy"
+        fi
+    else
+        source="dummy"
+    fi
+
+    printf "%s,%s,%s" "$percentage" "$watts" "$source"
+}
+
+# Windows under Cygwin or MSYS2: WMI through PowerShell, one call per
+# sample. Win32_Battery has the charge percentage; the discharge rate (mW)
+# and AC state are only in the root/wmi BatteryStatus class. Prints "pct N",
+# "rate MW" and "online True|False" lines, one set per battery.
+WINDOWS_BATTERY_PS='Get-CimInstance Win32_Battery | ForEach-Object { "pct " + $_.EstimatedChargeRemaining }; Get-CimInstance -Namespace root/wmi -ClassName BatteryStatus -ErrorAction SilentlyContinue | ForEach-Object { "rate " + $_.DischargeRate; "online " + $_.PowerOnline }'
+
+windows_battery_status() {
+    command -v powershell.exe >/dev/null 2>&1 || return 0
+    powershell.exe -NoProfile -NonInteractive -Command "$WINDOWS_BATTERY_PS" 2>/dev/null | tr -d '\r' || true
+}
+
+# With several batteries percentages are averaged and discharge rates summed
+windows_battery() {
+    windows_battery_status | awk '
+        $1 == "pct" && $2 ~ /^[0-9]+$/ { pct += $2; n++ }
+        $1 == "rate" && $2 ~ /^[0-9]+$/ { mw += $2; rated = 1 }
+        END {
+            if (!n) { printf ",,dummy"; exit }
+            printf "%.0f,%s,wmi", pct / n, rated ? sprintf("%.3f", mw / 1000) : ""
+        }'
+}
+
+# UPower battery object, found once per logging session; empty without
+# busctl(1) or upower(1) or a UPower battery. UPower names its objects
+# after the kernel's power supply but does not promise to, so a pinned
+# battery is matched on each device's NativePath rather than on the name.
+UPOWER_DEVICE=""
+
+upower_devices() {
+    if command -v busctl >/dev/null 2>&1; then
+        busctl --system call org.freedesktop.UPower /org/freedesktop/UPower org.freedesktop.UPower \
+            EnumerateDevices 2>/dev/null | tr ' ' '\n' | tr -d '"' | grep '^/'
+    else
+        upower -e 2>/dev/null
+    fi
+}
+
+upower_native_path() {
+    if command -v busctl >/dev/null 2>&1; then
+        busctl --system get-property org.freedesktop.UPower "$1" org.freedesktop.UPower.Device \
+            NativePath 2>/dev/null | awk '$1 == "s" { gsub(/"/, "", $2); print $2 }'
+    else
+        upower -i "$1" 2>/dev/null | awk '$1 == "native-path:" { print $2; exit }'
+    fi
+}
+
+upower_battery_path() {
+    local device
+    for device in $(upower_devices | grep '/battery_'); do
+        if [ -n "$SENSOR_BATTERY" ]; then
+            [ "$(upower_native_path "$device")" = "$SENSOR_BATTERY" ] || continue
+        else
+            # Peripheral batteries (battery_hidpp_*) come before the laptop's
+            case "$device" in *BAT*) ;; *) continue ;; esac
+        fi
+        echo "$device"
+        return 0
+    done
+    return 1
+}
+
+# Print "pct,watts" from the typed Percentage and EnergyRate properties of
+# UPOWER_DEVICE, one process per sample where upower -i takes two and
+# prints every property as text
+get_battery_upower_dbus() {
+    [ -n "$UPOWER_DEVICE" ] && command -v busctl >/dev/null 2>&1 || return 1
+    busctl --system get-property org.freedesktop.UPower "$UPOWER_DEVICE" org.freedesktop.UPower.Device \
+        Percentage EnergyRate 2>/dev/null | awk '
+        $1 == "d" { v[++n] = $2 }
+        END { if (n != 2) exit 1; printf "%g,%g\n", v[1], v[2] }'
+}
+
+# UPower, over D-Bus where busctl is available, otherwise with upower(1)
+linux_battery_upower() {
+    local percentage="" watts="" reading
+    if reading=$(get_battery_upower_dbus); then
+        percentage=${reading%,*}
+        watts=${reading#*,}
+    elif [ -n "$UPOWER_DEVICE" ] && command -v upower >/dev/null 2>&1; then
+        local upower_output
+        upower_output=$(upower -i "$UPOWER_DEVICE" 2>/dev/null || true)
+        # Match whole keys: "power supply: yes" precedes energy-rate
+        percentage=$(echo "$upower_output" | awk '$1 == "percentage:" { sub(/%/, "", $2); print $2; exit }')
+        watts=$(echo "$upower_output" | awk '$1 == "energy-rate:" { print $2; exit }')
+    fi
+    [ -n "$percentage" ] || return 1
+    printf "%s,%s,upower" "$percentage" "${watts:-5.0}"
+}
+
+# /sys/class/power_supply, which has the charge but not always the draw
+linux_battery_sysfs() {
+    local supply="$SYSFS/class/power_supply/${SENSOR_BATTERY:-BAT0}"
+    local percentage
+    percentage=$(cat "$supply/capacity" 2>/dev/null) || return 1
+    printf "%s,5.0,sysfs" "$percentage"
+}
+
+linux_battery() {
+    linux_battery_upower || linux_battery_sysfs || printf "50,5.0,dummy"
+}
+
+macos_battery() {
+    local percentage="50"
+    local watts="5.0"
+    local source="ioreg"
+
+    if command -v ioreg >/dev/null 2>&1; then
+        local ioreg_output
+        ioreg_output=$(ioreg -rn AppleSmartBattery 2>/dev/null || true)
+
+        if [ -n "$ioreg_output" ]; then
+            # Get current capacity percentage
+            local max_cap=$(echo "$ioreg_output" | grep -o '"MaxCapacity" = [0-9]*' | awk '{print $3}' || echo "100")
+            local current_cap=$(echo "$ioreg_output" | grep -o '"CurrentCapacity" = [0-9]*' | awk '{print $3}' || echo "50")
+
+            if [ "$max_cap" -gt 0 ]; then
+                percentage=$(echo "$current_cap $max_cap" | awk '{printf "%.0f", ($1/$2)*100}')
+            else
+                percentage="50"
+            fi
+
+            # Get power consumption more carefully
+            local amperage=$(echo "$ioreg_output" | grep -o '"InstantAmperage" = -\?[0-9]*' | awk '{print $3}' || echo "1000")
+            local voltage=$(echo "$ioreg_output" | grep -o '"Voltage" = [0-9]*' | awk '{print $3}' || echo "5000")
+
+            # Calculate watts from amperage (mA) and voltage (mV)
+            if [ -n "$amperage" ] && [ -n "$voltage" ] && [ "$voltage" -gt 0 ]; then
+                # Convert mA * mV to watts: (mA * mV) / 1,000,000
+                watts=$(echo "$amperage $voltage" | awk '{
+                    amp = $1;
+                    if (amp < 0) amp = -amp;
+                    volt = $2;
+                    watts = (amp * volt) / 1000000;
+                    if (watts < 0.1) watts = 5.0;
+                    if (watts > 100) watts = 5.0;
+                    printf "%.3f", watts
+                }' || echo "5.0")
+            else
+                watts="5.0"
+            fi
+        else
+            source="dummy"
+        fi
+    else
+        source="dummy"
+    fi
+
+    printf "%s,%s,%s" "$percentage" "$watts" "$source"
+}
+
+# Design and last full charge capacity of the batteries in mWh as
+# "design<TAB>full", for the run metadata: battery wear changes what a
+# percentage point is worth. From sysfs energy counters on Linux and
+# powercfg /batteryreport on Windows; empty where unknown.
+get_battery_capacity() {
+    case "$(detect_platform)" in
+        linux)
+            local supply
+            for supply in "$SYSFS"/class/power_supply/*; do
+                [ "$(cat "$supply/type" 2>/dev/null)" = "Battery" ] || continue
+                printf '%s %s\n' "$(cat "$supply/energy_full_design" 2>/dev/null)" "$(cat "$supply/energy_full" 2>/dev/null)"
+            done | awk '$1 > 0 { d += $1 / 1000; f += $2 / 1000 } END { if (d) printf "%d\t%d\n", d, f }'
+            ;;
+        windows)
+            command -v powercfg.exe >/dev/null 2>&1 && command -v cygpath >/dev/null 2>&1 || return 0
+            local dir=$(mktemp -d "${TMPDIR:-/tmp}/batlab-battery.XXXXXX")
+            powercfg.exe /batteryreport /xml /output "$(cygpath -w "$dir/report.xml")" >/dev/null 2>&1 || true
+            # One <Battery> element per battery, with a DesignCapacity and
+            # FullChargeCapacity element each
+            awk -F'>' 'BEGIN { RS = "<" }
+                $1 == "DesignCapacity" { d += $2 }
+                $1 == "FullChargeCapacity" { f += $2 }
+                END { if (d) printf "%d\t%d\n", d, f }' "$dir/report.xml" 2>/dev/null || true
+            rm -rf "$dir"
+            ;;
+    esac
+}
+
+# Controlled vocabulary for the "src" field of a sample. Analysis branches
+# on provenance, so new backends must add their name here (and to the man
+# page) rather than invent one ad hoc.
+#   upower    UPower D-Bus battery device (Linux)
+#   sysfs     /sys/class/power_supply (Linux)
+#   acpiconf  acpiconf(8) battery unit (FreeBSD)
+#   sysctl    hw.acpi.battery sysctls (FreeBSD)
+#   apm       apm(8) (OpenBSD)
+#   envstat   envstat(8) sensors (NetBSD)
+#   ioreg     AppleSmartBattery registry entry (macOS)
+#   wmi       Win32_Battery and root/wmi BatteryStatus (Windows)
+#   rapl      Intel RAPL package energy counters
+#   estimate  derived values, e.g. power from capacity change
+#   mock      the mock telemetry provider, never a real battery
+#   dummy     no battery found; placeholder values
+SOURCES="upower sysfs acpiconf sysctl apm envstat ioreg wmi rapl estimate mock dummy"
+
+is_known_source() {
+    case " $SOURCES " in
+        *" $1 "*) return 0 ;;
+    esac
+    return 1
+}
+
+# Raw electrical readings of the battery as "voltage_v,current_a,temp_c",
+# empty where unavailable, for sanity-checking the derived watts and
+# studying voltage sag under load. Linux reads voltage_now, current_now and
+# temp of the battery's sysfs supply; FreeBSD the present voltage from
+# acpiconf, averaged over the batteries, and the present rate when the
+# battery reports it in mA rather than mW, summed.
+get_battery_electrical() {
+    case "$(detect_platform)" in
+        linux)
+            local supply="$SYSFS/class/power_supply/${SENSOR_BATTERY:-BAT0}"
+            [ -d "$supply" ] || return 0
+            awk -v uv="$(cat "$supply/voltage_now" 2>/dev/null)" -v ua="$(cat "$supply/current_now" 2>/dev/null)" \
+                -v dc="$(cat "$supply/temp" 2>/dev/null)" 'BEGIN {
+                num = "^-?[0-9]+$"
+                printf "%s,%s,%s\n", (uv ~ num ? sprintf("%.3f", uv / 1e6) : ""),
+                    (ua ~ num ? sprintf("%.3f", ua / 1e6) : ""), (dc ~ num ? sprintf("%.1f", dc / 10) : "")
+            }'
+            ;;
+        freebsd)
+            command -v acpiconf >/dev/null 2>&1 || return 0
+            local unit
+            for unit in ${BATTERY_UNITS:-0}; do
+                acpiconf -i "$unit" 2>/dev/null || true
+            done | awk -F':[ \t]*' '
+                $1 ~ /^Present voltage/ && $2 ~ /^[0-9]+ mV/ { mv += $2; nv++ }
+                $1 ~ /^Present rate/ && $2 ~ /^[0-9]+ mA/ { ma += $2; na++ }
+                END {
+                    if (nv || na) printf "%s,%s,\n", (nv ? sprintf("%.3f", mv / nv / 1000) : ""),
+                        (na ? sprintf("%.3f", ma / 1000) : "")
+                }'
+            ;;
+    esac
+}
+
+# Telemetry providers. A provider NAME supplies readings through the
+# functions NAME_battery (printing PCT,WATTS,SOURCE), NAME_battery_state,
+# NAME_cpu_load, NAME_memory and NAME_temperature, and optionally
+# NAME_init, run once when
+# the sensors are resolved; the get_* functions below call those of the
+# selected provider and fall back to portable readings for any it
+# leaves out. Each platform is a provider named by detect_platform, whose
+# battery is read from its sources in turn (NAME_battery_SOURCE, e.g.
+# linux_battery_upower then linux_battery_sysfs). [sensors] provider picks
+# another provider and [sensors] battery_sources stacks sources
+# explicitly; scripts sourcing this file can define their own provider and
+# set TELEMETRY_PROVIDER.
+TELEMETRY_PROVIDER=""
+BATTERY_SOURCES=""
+
+telemetry_provider() {
+    printf '%s\n' "${TELEMETRY_PROVIDER:-$(detect_platform)}"
+}
+
+# Succeed if the selected provider supplies METRIC
+provider_has() {
+    command -v "$(telemetry_provider)_$1" >/dev/null 2>&1
+}
+
+provider_call() {
+    "$(telemetry_provider)_$1"
+}
+
+get_battery_info() {
+    if [ -n "$BATTERY_SOURCES" ]; then
+        local source
+        for source in $BATTERY_SOURCES; do
+            provider_has "battery_$source" && provider_call "battery_$source" && return 0
+        done
+        printf ",,dummy"
+    elif provider_has battery; then
+        provider_call battery
+    else
+        printf ",,dummy"
+    fi
+}
+
+# Whether the battery is discharging, charging or full, or unknown, as
+# recorded in the "state" field of each sample. Charging and full samples
+# describe the charger rather than drain, and reports leave them out.
+# Platforms that cannot tell fall back to the AC line: off AC the battery
+# is discharging, on AC the state is unknown.
+linux_battery_state() {
+    case "$(cat "$SYSFS/class/power_supply/${SENSOR_BATTERY:-BAT0}/status" 2>/dev/null)" in
+        Discharging) echo discharging ;;
+        Charging) echo charging ;;
+        # "Not charging": on AC, held below full by a charge threshold
+        Full|"Not charging") echo full ;;
+        *) return 1 ;;
+    esac
+}
+
+# hw.acpi.battery.state is a bit mask: 1 discharging, 2 charging, 4 critical
+freebsd_battery_state() {
+    local state
+    state=$(sysctl_get hw.acpi.battery.state) || return 1
+    case "$state" in
+        ''|*[!0-9]*) return 1 ;;
+    esac
+    if [ $((state & 2)) -ne 0 ]; then
+        echo charging
+    elif [ $((state & 1)) -ne 0 ]; then
+        echo discharging
+    else
+        echo full
+    fi
+}
+
+macos_battery_state() {
+    pmset -g batt 2>/dev/null | awk '
+        /; discharging;/ { print "discharging"; found = 1; exit }
+        /; charging;/ { print "charging"; found = 1; exit }
+        /; charged;/ { print "full"; found = 1; exit }
+        END { exit !found }'
+}
+
+get_battery_state() {
+    if provider_has battery_state && provider_call battery_state; then
+        return 0
+    fi
+    case "$(get_ac_state)" in
+        offline) echo discharging ;;
+        *) echo unknown ;;
+    esac
+}
+
+# Mock provider ([sensors] provider = "mock") for testing and demos without
+# a laptop on battery. Readings are a function of the time since the
+# sensors were resolved: the battery drains linearly from [mock] start_pct
+# at drain_pct_per_hour while drawing watts, with uniform noise of up to
+# +/- noise on every reading, seeded by seed, the sample number
+# (SAMPLER_SAMPLES) and the reading so that runs replay exactly. With [mock] script, readings instead come from a file of
+# "SECONDS PCT WATTS CPU_LOAD RAM_PCT TEMP_C" lines, each holding from its
+# time until the next line's ("-" or a missing column keeps the default).
+MOCK_START=""
+
+mock_init() {
+    MOCK_START=$(date +%s)
+    MOCK_START_PCT=$(config_get mock start_pct)
+    MOCK_DRAIN=$(config_get mock drain_pct_per_hour)
+    MOCK_WATTS=$(config_get mock watts)
+    MOCK_NOISE=$(config_get mock noise)
+    MOCK_SEED=$(config_get mock seed)
+    MOCK_SCRIPT=$(config_get mock script)
+    if [ -n "$MOCK_SCRIPT" ] && [ ! -r "$MOCK_SCRIPT" ]; then
+        log_warn "Mock script not readable: $MOCK_SCRIPT"
+        MOCK_SCRIPT=""
+    fi
+}
+
+# Print reading FIELD (pct, watts, cpu_load, ram_pct or temp_c)
+mock_reading() {
+    local elapsed=$(( $(date +%s) - ${MOCK_START:-$(date +%s)} ))
+    awk -v field="$1" -v t="$elapsed" -v idx="${SAMPLER_SAMPLES:-0}" -v start="${MOCK_START_PCT:-100}" -v drain="${MOCK_DRAIN:-10}" \
+        -v watts="${MOCK_WATTS:-8}" -v noise="${MOCK_NOISE:-0}" -v seed="${MOCK_SEED:-1}" '
+        BEGIN {
+            col["pct"] = 2; col["watts"] = 3; col["cpu_load"] = 4; col["ram_pct"] = 5; col["temp_c"] = 6
+            v["pct"] = start - drain * t / 3600; v["watts"] = watts
+            v["cpu_load"] = 0.25; v["ram_pct"] = 40; v["temp_c"] = 45
+        }
+        !/^[ \t]*(#|$)/ && $1 <= t { if ($col[field] != "" && $col[field] != "-") v[field] = $col[field] }
+        END {
+            srand(seed * 100003 + idx * 31 + col[field])
+            x = v[field] + noise * (2 * rand() - 1)
+            if (field == "pct") { x = x < 0 ? 0 : (x > 100 ? 100 : x); printf "%.0f\n", x }
+            else printf "%.3f\n", (x < 0 ? 0 : x)
+        }' ${MOCK_SCRIPT:-/dev/null}
+}
+
+mock_battery() {
+    printf "%s,%s,mock" "$(mock_reading pct)" "$(mock_reading watts)"
+}
+
+mock_battery_state() {
+    echo discharging
+}
+
+mock_cpu_load() {
+    mock_reading cpu_load
+}
+
+mock_memory() {
+    mock_reading ram_pct
+}
+
+mock_temperature() {
+    mock_reading temp_c
+}
+
+# System metrics collection
+get_cpu_load() {
+    if provider_has cpu_load; then
+        provider_call cpu_load
+        return
+    fi
+
+    # A load that cannot be read is reported as none (null in the sample),
+    # never as a made-up value that would skew averages
+    local load=""
+    if command -v uptime >/dev/null 2>&1; then
+        # Try different uptime formats
+        local uptime_output=$(uptime)
+        if echo "$uptime_output" | grep -q "load average"; then
+            load=$(echo "$uptime_output" | awk -F'load average:' '{print $2}' | awk '{gsub(/^[ \t]+|[ \t]+$/, "", $1); print $1}' | tr -d ',')
+        elif echo "$uptime_output" | grep -q "load averages"; then
+            # macOS format: "load averages: 1.23 1.45 1.67"
+            load=$(echo "$uptime_output" | awk -F'load averages:' '{print $2}' | awk '{gsub(/^[ \t]+|[ \t]+$/, "", $1); print $1}')
+        fi
+
+        # Validate the load value is numeric
+        if ! echo "$load" | grep -E '^[0-9]+\.?[0-9]*$' >/dev/null; then
+            load=""
+        fi
+    elif [ -r /proc/loadavg ]; then
+        load=$(cut -d' ' -f1 /proc/loadavg)
+    fi
+    printf "%s" "$load"
+}
+
+# Screen backlight level in percent of its maximum, or nothing without a
+# controllable backlight. Linux reads the first /sys/class/backlight device;
+# FreeBSD the ACPI video brightness of lcd0, which is already a percentage.
+get_backlight_pct() {
+    case "$(detect_platform)" in
+        linux)
+            local dev
+            for dev in "$SYSFS"/class/backlight/*; do
+                [ -r "$dev/brightness" ] && [ -r "$dev/max_brightness" ] || continue
+                awk -v cur="$(cat "$dev/brightness")" -v max="$(cat "$dev/max_brightness")" \
+                    'BEGIN { if (max > 0) printf "%.1f\n", cur * 100 / max }'
+                return 0
+            done
+            ;;
+        freebsd)
+            sysctl_get hw.acpi.video.lcd0.brightness | awk '/^[0-9]+$/ { print; exit }'
+            ;;
+    esac
+}
+
+# Cumulative KB read and written by whole disks since boot, or nothing when
+# unavailable. Rates are derived from consecutive samples during analysis.
+get_disk_io_kb() {
+    local platform=$(detect_platform)
+    case "$platform" in
+        linux)
+            # Fields 3 and 7 of /sys/block/*/stat are sectors read and written
+            for stat in "$SYSFS"/block/*/stat; do
+                case "$stat" in
+                    */loop*|*/ram*|*/zram*|*/dm-*|*/md*|*/sr*) continue ;;
+                esac
+                [ -r "$stat" ] && cat "$stat"
+            done | awk '{ s += $3 + $7; n++ } END { if (n) printf "%.0f\n", s / 2 }'
+            ;;
+        freebsd)
+            # iostat -I reports totals since boot: KB/t xfrs MB per device
+            if command -v iostat >/dev/null 2>&1; then
+                iostat -I -d -n 64 2>/dev/null | tail -1 | \
+                    awk '{ for (i = 3; i <= NF; i += 3) s += $i; n++ } END { if (n) printf "%.0f\n", s * 1024 }'
+            fi
+            ;;
+    esac
+}
+
+# Percentage of memory in use according to /proc/meminfo, or nothing
+meminfo_used_pct() {
+    [ -r /proc/meminfo ] || return 0
+    local total=$(grep MemTotal /proc/meminfo | awk '{print $2}')
+    local available=$(grep MemAvailable /proc/meminfo | awk '{print $2}' || grep MemFree /proc/meminfo | awk '{print $2}')
+    if [ -n "$total" ] && [ -n "$available" ] && [ "$total" -gt 0 ]; then
+        echo "$total $available" | awk '{print (($1-$2)/$1)*100}'
+    fi
+}
+
+# Pages neither free nor inactive (nor cache, before FreeBSD 12) count as
+# used; other BSDs lack vm.stats and report none
+freebsd_memory() {
+    local total=$(sysctl_get vm.stats.vm.v_page_count)
+    [ -n "$total" ] || return 0
+    awk -v total="$total" -v free="$(sysctl_get vm.stats.vm.v_free_count)" \
+        -v inactive="$(sysctl_get vm.stats.vm.v_inactive_count)" -v cache="$(sysctl_get vm.stats.vm.v_cache_count)" \
+        'BEGIN { if (total > 0 && free != "") printf "%.1f", (total - free - inactive - cache) * 100 / total }'
+}
+
+openbsd_memory() {
+    freebsd_memory
+}
+
+netbsd_memory() {
+    freebsd_memory
+}
+
+linux_memory() {
+    meminfo_used_pct
+}
+
+# Active, wired and compressed pages of all pages vm_stat(1) accounts for,
+# close to "Memory Used" in Activity Monitor
+macos_memory() {
+    command -v vm_stat >/dev/null 2>&1 || return 0
+    vm_stat 2>/dev/null | awk -F':[ \t]*' '
+    { v = $2; sub(/\.$/, "", v) }
+    /^Pages (free|inactive|speculative)/ { total += v }
+    /^Pages (active|wired down|occupied by compressor)/ { total += v; used += v }
+    END { if (total > 0) printf "%.1f", used * 100 / total }'
+}
+
+get_memory_usage() {
+    if provider_has memory; then
+        provider_call memory
+    else
+        # Best effort: Cygwin and MSYS2 provide /proc/meminfo
+        meminfo_used_pct
+    fi
+}
+
+# dev.cpu.N.temperature needs coretemp(4) or amdtemp(4) loaded; the ACPI
+# thermal zone is there on most laptops without either
+freebsd_temperature() {
+    local t
+    t=$(sysctl_get dev.cpu.0.temperature) || t=""
+    [ -n "$t" ] || t=$(sysctl_get hw.acpi.thermal.tz0.temperature) || return 1
+    [ -n "$t" ] || return 1
+    printf '%s\n' "${t%%C*}"
+}
+
+linux_temperature() {
+    awk '{ print $1 / 1000; exit }' "$SYSFS/class/thermal/thermal_zone0/temp" 2>/dev/null
+}
+
+get_temperature() {
+    # A pinned sensor that reads nothing falls through to the provider
+    local t=""
+    case "$SENSOR_TEMP" in
+        sysctl:*)
+            t=$(sysctl_get "${SENSOR_TEMP#sysctl:}") || t=""
+            t="${t%%C*}"
+            ;;
+        /*)
+            t=$(awk '{ printf "%s", $1 / 1000; exit }' "$SENSOR_TEMP" 2>/dev/null) || t=""
+            ;;
+    esac
+    if [ -n "$t" ]; then
+        printf '%s' "$t"
+        return 0
+    fi
+
+    # Without a sensor backend the temperature is unknown (null), not guessed
+    if provider_has temperature; then
+        provider_call temperature | tr -d '\n'
+    fi
+}
+
+# System metadata collection
+get_hostname() {
+    hostname 2>/dev/null || echo "unknown"
+}
+
+get_os_info() {
+    local platform=$(detect_platform)
+    case "$platform" in
+        freebsd)  uname -sr | sed 's/FreeBSD /FreeBSD /' ;;
+        openbsd)  uname -sr | sed 's/OpenBSD /OpenBSD /' ;;
+        netbsd)   uname -sr | sed 's/NetBSD /NetBSD /' ;;
+        linux)
+            if [ -f /etc/os-release ]; then
+                grep '^PRETTY_NAME=' /etc/os-release | cut -d'"' -f2
+            else
+                uname -sr
+            fi
+            ;;
+        macos)    sw_vers -productName 2>/dev/null | head -1 || echo "macOS" ;;
+        *)        uname -sr ;;
+    esac
+}
+
+get_cpu_info() {
+    local platform=$(detect_platform)
+    local cpu="unknown"
+
+    case "$platform" in
+        freebsd|openbsd|netbsd)
+            if command -v sysctl >/dev/null 2>&1; then
+                cpu=$(sysctl -n hw.model 2>/dev/null || echo "unknown")
+            fi
+            ;;
+        linux)
+            if [ -f /proc/cpuinfo ]; then
+                cpu=$(grep "model name" /proc/cpuinfo | head -1 | cut -d':' -f2 | sed 's/^ *//' || echo "unknown")
+            fi
+            ;;
+        macos)
+            cpu=$(sysctl -n machdep.cpu.brand_string 2>/dev/null || echo "unknown")
+            ;;
+    esac
+
+    printf "%s" "$cpu"
+}
+
+# CPU topology as "cores<TAB>threads<TAB>smt<TAB>online": physical cores and
+# hardware threads online, SMT state (on, off, notsupported) and the online
+# CPU list. Fields that cannot be determined are empty.
+get_cpu_topology() {
+    local cores=""
+    local threads=""
+    local smt=""
+    local online=""
+
+    case "$(detect_platform)" in
+        linux)
+            local cpu_dir="$SYSFS/devices/system/cpu"
+            online=$(cat "$cpu_dir/online" 2>/dev/null || true)
+            threads=$(getconf _NPROCESSORS_ONLN 2>/dev/null || true)
+            # Each distinct sibling list is one core
+            cores=$(cat "$cpu_dir"/cpu[0-9]*/topology/thread_siblings_list 2>/dev/null | sort -u | wc -l | tr -d ' ')
+            [ "$cores" = "0" ] && cores=""
+            smt=$(cat "$cpu_dir/smt/control" 2>/dev/null || true)
+            case "$smt" in
+                forceoff) smt="off" ;;
+                notimplemented) smt="notsupported" ;;
+            esac
+            ;;
+        freebsd)
+            threads=$(sysctl -n hw.ncpu 2>/dev/null || true)
+            cores=$(sysctl -n kern.smp.cores 2>/dev/null || true)
+            local per_core=$(sysctl -n kern.smp.threads_per_core 2>/dev/null || true)
+            local allowed=$(sysctl -n machdep.hyperthreading_allowed 2>/dev/null || true)
+            if [ "$per_core" = "1" ] && [ -z "$allowed" ]; then
+                smt="notsupported"
+            elif [ "$allowed" = "0" ] || [ "$per_core" = "1" ]; then
+                smt="off"
+            elif [ -n "$per_core" ]; then
+                smt="on"
+            fi
+            [ -n "$threads" ] && online="0-$((threads - 1))"
+            ;;
+        macos)
+            cores=$(sysctl -n hw.physicalcpu 2>/dev/null || true)
+            threads=$(sysctl -n hw.logicalcpu 2>/dev/null || true)
+            ;;
+    esac
+
+    printf '%s\t%s\t%s\t%s\n' "$cores" "$threads" "$smt" "$online"
+}
+
+# Power-management packages whose versions are recorded in run metadata;
+# override with [metadata] packages = "..." in batlab.toml
+DEFAULT_PACKAGES="upower powerd tlp power-profiles-daemon"
+
+# Installed version of a package, or nothing when it is not installed.
+# powerd ships with the FreeBSD base system, so it reports the userland version.
+get_package_version() {
+    local name="$1"
+    local platform=$(detect_platform)
+
+    case "$platform" in
+        freebsd)
+            if [ "$name" = "powerd" ]; then
+                command -v powerd >/dev/null 2>&1 && freebsd-version -u 2>/dev/null
+            else
+                pkg query '%v' "$name" 2>/dev/null
+            fi
+            ;;
+        openbsd)
+            pkg_info -q -I "$name" 2>/dev/null | sed -n 's/^'"$name"'-\([0-9][^ ]*\).*/\1/p' | head -1
+            ;;
+        netbsd)
+            pkg_info -q -E "$name" 2>/dev/null | sed 's/.*-//'
+            ;;
+        linux)
+            if command -v dpkg-query >/dev/null 2>&1; then
+                dpkg-query -W -f='${db:Status-Abbrev} ${Version}\n' "$name" 2>/dev/null | awk '$1 == "ii" { print $2 }'
+            elif command -v rpm >/dev/null 2>&1; then
+                rpm -q --qf '%{VERSION}-%{RELEASE}\n' "$name" 2>/dev/null | grep -v 'not installed'
+            elif command -v pacman >/dev/null 2>&1; then
+                pacman -Q "$name" 2>/dev/null | awk '{ print $2 }'
+            elif command -v apk >/dev/null 2>&1; then
+                apk info -e -v "$name" 2>/dev/null | sed "s/^$name-//"
+            fi
+            ;;
+        macos)
+            # pmset is part of the OS; there is nothing else to report
+            ;;
+    esac
+    return 0
+}
+
+# JSON object of installed package versions, e.g. {"upower": "1.90.2-1"}
+get_package_versions() {
+    local packages=$(config_get metadata packages)
+    local out=""
+
+    for name in ${packages:-$DEFAULT_PACKAGES}; do
+        local version=$(get_package_version "$name" | head -1)
+        [ -n "$version" ] || continue
+        out="${out}${out:+, }\"$(json_escape "$name")\": \"$(json_escape "$version")\""
+    done
+
+    printf '{%s}' "$out"
+}
+
+# Commit and dirty state of the workload directory as a JSON object, or
+# null when it is not in a git work tree, so results can be traced back to
+# the exact workload scripts that produced them
+get_workload_git() {
+    local commit
+    if ! command -v git >/dev/null 2>&1 || [ ! -d "$WORKLOAD_DIR" ] ||
+        ! commit=$(git -C "$WORKLOAD_DIR" rev-parse HEAD 2>/dev/null); then
+        printf 'null'
+        return 0
+    fi
+    local dirty=false
+    if [ -n "$(git -C "$WORKLOAD_DIR" status --porcelain -- . 2>/dev/null)" ]; then
+        dirty=true
+    fi
+    printf '{"commit": "%s", "dirty": %s}' "$(json_escape "$commit")" "$dirty"
+}
+
+# Active power-management state as "profile<TAB>tlp_mode": the
+# power-profiles-daemon profile (balanced, power-saver, performance) and the
+# TLP mode (AC, battery, possibly with "(manual)"). Empty when not in use.
+get_power_state() {
+    local profile=""
+    local tlp_mode=""
+
+    if [ "$(detect_platform)" = "linux" ]; then
+        if command -v powerprofilesctl >/dev/null 2>&1; then
+            profile=$(powerprofilesctl get 2>/dev/null || true)
+        fi
+        if command -v tlp-stat >/dev/null 2>&1; then
+            tlp_mode=$(tlp-stat -s 2>/dev/null | awk -F'=[ \t]*' '/^Mode/ { print $2; exit }' || true)
+        fi
+    fi
+
+    printf '%s\t%s\n' "$profile" "$tlp_mode"
+}
+
+# Whether get_power_state can report anything here: a power profile or TLP
+# mode set later in a run is still caught when both start out empty
+has_power_state() {
+    [ "$(detect_platform)" = "linux" ] || return 1
+    command -v powerprofilesctl >/dev/null 2>&1 || command -v tlp-stat >/dev/null 2>&1
+}
+
+# Power-management settings as "key<TAB>value" lines, the state presets are
+# checked against:
+#   os                  detect_platform
+#   power_profile       power-profiles-daemon profile (see get_power_state)
+#   tlp_mode            TLP mode
+#   governor, epp       cpufreq governor and energy performance preference
+#                       of cpu0 (Linux); hwpstate_intel EPP (FreeBSD)
+#   aspm_policy         PCIe ASPM policy (see get_device_power)
+#   smt                 SMT state (see get_cpu_topology)
+#   powerd              running or stopped (FreeBSD)
+#   powerd_flags        powerd_flags in rc.conf
+#   cx_lowest           deepest CPU idle state allowed
+# Settings that cannot be read are left out.
+get_system_state() {
+    local platform=$(detect_platform)
+    local power_state=$(get_power_state)
+    local cpufreq="$SYSFS/devices/system/cpu/cpu0/cpufreq"
+
+    printf 'os\t%s\n' "$platform"
+    printf '%s\n' "$power_state" | awk -F'\t' '
+        $1 != "" { printf "power_profile\t%s\n", $1 }
+        $2 != "" { printf "tlp_mode\t%s\n", $2 }'
+    case "$platform" in
+        linux)
+            if [ -r "$cpufreq/scaling_governor" ]; then
+                printf 'governor\t%s\n' "$(cat "$cpufreq/scaling_governor")"
+            fi
+            if [ -r "$cpufreq/energy_performance_preference" ]; then
+                printf 'epp\t%s\n' "$(cat "$cpufreq/energy_performance_preference")"
+            fi
+            get_device_power | awk -F'\t' '$1 == "aspm_policy"'
+            ;;
+        freebsd)
+            local value
+            if value=$(sysctl -n dev.hwpstate_intel.0.epp 2>/dev/null) && [ -n "$value" ]; then
+                printf 'epp\t%s\n' "$value"
+            fi
+            if pgrep -x powerd >/dev/null 2>&1; then
+                printf 'powerd\trunning\n'
+            else
+                printf 'powerd\tstopped\n'
+            fi
+            if command -v sysrc >/dev/null 2>&1 && value=$(sysrc -n powerd_flags 2>/dev/null); then
+                printf 'powerd_flags\t%s\n' "$value"
+            fi
+            if value=$(sysctl -n hw.acpi.cpu.cx_lowest 2>/dev/null) && [ -n "$value" ]; then
+                printf 'cx_lowest\t%s\n' "$value"
+            fi
+            ;;
+    esac
+    get_cpu_topology | awk -F'\t' '$3 != "" { printf "smt\t%s\n", $3 }'
+}
+
+# get_system_state as a one-line JSON object for metadata
+system_state_json() {
+    get_system_state | awk -F'\t' "$AWK_LIB"'
+        { out = out (NR > 1 ? ", " : "") json_quote($1) ": " json_quote($2) }
+        END { printf "{%s}", out }'
+}
+
+# Recommended configurations. A preset is a set of get_system_state
+# settings, as "preset<TAB>key<TAB>value" lines, that must all hold for a
+# run to carry its name. Runs logged under a preset's name, or with
+# log --preset, are checked before logging starts. [preset.NAME] sections
+# in batlab.toml add presets or replace a built-in one of the same name.
+BUILTIN_PRESETS="linux-ppd-powersave	os	linux
+linux-ppd-powersave	power_profile	power-saver
+linux-ppd-balanced	os	linux
+linux-ppd-balanced	power_profile	balanced
+linux-ppd-performance	os	linux
+linux-ppd-performance	power_profile	performance
+linux-tlp-battery	os	linux
+linux-tlp-battery	tlp_mode	battery
+linux-powersave-epp	os	linux
+linux-powersave-epp	governor	powersave
+linux-powersave-epp	epp	power
+linux-aspm-powersupersave	os	linux
+linux-aspm-powersupersave	aspm_policy	powersupersave
+freebsd-powerd-adaptive	os	freebsd
+freebsd-powerd-adaptive	powerd	running
+freebsd-powerd-adaptive	powerd_flags	-a adaptive -b adaptive
+freebsd-powerd-adaptive	cx_lowest	Cmax
+freebsd-powerd-hiadaptive	os	freebsd
+freebsd-powerd-hiadaptive	powerd	running
+freebsd-powerd-hiadaptive	powerd_flags	-a hiadaptive -b adaptive
+freebsd-powerd-hiadaptive	cx_lowest	Cmax
+freebsd-default	os	freebsd
+freebsd-default	powerd	stopped"
+
+# Print "preset<TAB>key<TAB>value" for every preset setting, those from
+# batlab.toml first
+preset_entries() {
+    config_entries preset | awk -F'\t' -v builtin="$BUILTIN_PRESETS" '
+        { print; own[$1] = 1 }
+        END {
+            n = split(builtin, b, "\n")
+            for (i = 1; i <= n; i++) {
+                split(b[i], f, "\t")
+                if (!(f[1] in own)) print b[i]
+            }
+        }'
+}
+
+is_preset() {
+    preset_entries | cut -f1 | grep -qxF "$1"
+}
+
+# Print how the system differs from preset NAME, one
+# "key: expected VALUE, found VALUE" line per setting, and fail if it does
+check_preset() {
+    get_system_state | awk -F'\t' -v preset="$1" -v entries="$(preset_entries)" '
+        { state[$1] = $2; known[$1] = 1 }
+        END {
+            n = split(entries, e, "\n")
+            for (i = 1; i <= n; i++) {
+                split(e[i], f, "\t")
+                if (f[1] != preset) continue
+                if (!(f[2] in known)) { printf "%s: expected %s, found nothing\n", f[2], f[3]; bad = 1 }
+                else if (state[f[2]] != f[3]) { printf "%s: expected %s, found %s\n", f[2], f[3], state[f[2]]; bad = 1 }
+            }
+            exit bad
+        }'
+}
+
+# Preset check before logging: PRESET (log --preset) or the configuration
+# name when it names a preset. On mismatch PRESET_POLICY (--preset-policy,
+# [presets] mismatch) decides: fail refuses to log, warn continues and the
+# differences are recorded in the metadata as preset_mismatch.
+PRESET=""
+PRESET_POLICY=""
+PRESET_MISMATCH=""
+
+verify_preset() {
+    local config_name="$1"
+    local policy="${PRESET_POLICY:-$(config_get presets mismatch)}"
+    policy="${policy:-fail}"
+    PRESET_MISMATCH=""
+
+    case "$policy" in
+        fail|warn) ;;
+        *)
+            log_error "Unknown preset mismatch policy: $policy (expected fail or warn)"
+            return 1
+            ;;
+    esac
+
+    if [ -n "$PRESET" ]; then
+        if ! is_preset "$PRESET"; then
+            log_error "Unknown preset: $PRESET (see batlab preset list)"
+            return 1
+        fi
+    elif is_preset "$config_name"; then
+        PRESET="$config_name"
+    else
+        return 0
+    fi
+
+    if PRESET_MISMATCH=$(check_preset "$PRESET"); then
+        log_info "System matches preset $PRESET"
+        return 0
+    fi
+    if [ "$policy" = fail ]; then
+        log_error "System does not match preset $PRESET:"
+        printf '%s\n' "$PRESET_MISMATCH" | sed 's/^/    /' >&2
+        log_error "Change the settings, or use --preset-policy warn to log anyway"
+        return 1
+    fi
+    log_warn "System does not match preset $PRESET; the differences are recorded with the run:"
+    printf '%s\n' "$PRESET_MISMATCH" | sed 's/^/    /' >&2
+}
+
+# Runtime power state of each USB and PCI device as "device<TAB>state"
+# lines, so a device kept at full power under one OS shows up when runs are
+# compared. On Linux USB devices are "usb PORT VID:PID" with their
+# power/control and runtime_status ("auto/suspended"), PCI devices add the
+# ASPM link states enabled in sysfs; on FreeBSD the usbconfig power mode and
+# the pciconf ASPM and D state are used.
+get_device_power() {
+    local dev
+    case "$(detect_platform)" in
+        linux)
+            if [ -r "$SYSFS/module/pcie_aspm/parameters/policy" ]; then
+                printf 'aspm_policy\t%s\n' "$(sed 's/.*\[\(.*\)\].*/\1/' "$SYSFS/module/pcie_aspm/parameters/policy")"
+            fi
+            for dev in "$SYSFS"/bus/usb/devices/*; do
+                [ -r "$dev/idVendor" ] && [ -r "$dev/power/control" ] || continue
+                printf 'usb %s %s:%s\t%s/%s\n' "${dev##*/}" "$(cat "$dev/idVendor")" "$(cat "$dev/idProduct")" \
+                    "$(cat "$dev/power/control")" "$(cat "$dev/power/runtime_status" 2>/dev/null || echo unknown)"
+            done
+            for dev in "$SYSFS"/bus/pci/devices/*; do
+                [ -r "$dev/power/control" ] || continue
+                local aspm=""
+                local link
+                for link in l0s l1 l1_1 l1_2; do
+                    if [ "$(cat "$dev/link/${link}_aspm" 2>/dev/null)" = 1 ]; then
+                        aspm="$aspm${aspm:+,}$(printf '%s' "$link" | tr 'l_' 'L.')"
+                    fi
+                done
+                printf 'pci %s\t%s%s/%s\n' "${dev##*/}" "${aspm:+aspm $aspm }" \
+                    "$(cat "$dev/power/control")" "$(cat "$dev/power/runtime_status" 2>/dev/null || echo unknown)"
+            done
+            ;;
+        freebsd)
+            if command -v usbconfig >/dev/null 2>&1; then
+                usbconfig list 2>/dev/null | awk '
+                    match($0, /pwr=[A-Z]+/) { d = $1; sub(/:$/, "", d); printf "usb %s\t%s\n", d, tolower(substr($0, RSTART + 4, RLENGTH - 4)) }'
+            fi
+            pciconf -lc 2>/dev/null | awk '
+                function flush() { if (dev != "") printf "pci %s\t%s\n", dev, (aspm != "" ? "aspm " aspm " " : "") (d != "" ? d : "unknown") }
+                /^[^ \t]/ { flush(); dev = $1; sub(/:$/, "", dev); aspm = ""; d = ""; next }
+                match($0, /ASPM [^ (]+/) { aspm = substr($0, RSTART + 5, RLENGTH - 5) }
+                match($0, /current D[0-3]/) { d = substr($0, RSTART + 8, 2) }
+                END { flush() }'
+            ;;
+    esac
+}
+
+# Device power states as a one-line JSON object for the metadata
+device_power_json() {
+    get_device_power | awk -F'\t' "$AWK_LIB"'
+        { out = out (NR > 1 ? ", " : "") json_quote($1) ": " json_quote($2) }
+        END { printf "{%s}", out }'
+}
+
+# Record power profile changes during logging as annotations. Checked at
+# most every POWER_STATE_INTERVAL seconds since powerprofilesctl is slow.
+POWER_STATE_INTERVAL=30
+POWER_STATE=""
+POWER_STATE_CHECKED=""
+
+check_power_state() {
+    local now=$(date +%s)
+    if [ -n "$POWER_STATE_CHECKED" ] && [ $((now - POWER_STATE_CHECKED)) -lt "$POWER_STATE_INTERVAL" ]; then
+        return 0
+    fi
+    POWER_STATE_CHECKED="$now"
+
+    local state=$(get_power_state)
+    if [ "$state" != "$POWER_STATE" ]; then
+        local change=$(printf '%s\n%s\n' "$POWER_STATE" "$state" | awk -F'\t' '
+            NR == 1 { p = $1; m = $2 }
+            NR == 2 {
+                if ($1 != p) out = "profile " (p == "" ? "none" : p) " -> " ($1 == "" ? "none" : $1)
+                if ($2 != m) out = out (out == "" ? "" : ", ") "tlp " (m == "" ? "none" : m) " -> " ($2 == "" ? "none" : $2)
+                print out
+            }')
+        log_info "Power state changed: $change"
+        annotate "$1" power_profile "$change"
+        POWER_STATE="$state"
+    fi
+}
+
+# Whether the machine runs on AC power: "online", "offline", or nothing when
+# it cannot be told (no adapter reported, unsupported platform)
+get_ac_state() {
+    local online=""
+    case "$(detect_platform)" in
+        linux)
+            local supply
+            for supply in "$SYSFS"/class/power_supply/*; do
+                [ "$(cat "$supply/type" 2>/dev/null)" = "Mains" ] || continue
+                online=$(cat "$supply/online" 2>/dev/null || true)
+                [ "$online" = "1" ] && break
+            done
+            if [ -z "$online" ] && command -v upower >/dev/null 2>&1; then
+                online=$(upower -e 2>/dev/null | grep line_power | head -1 | xargs -r upower -i 2>/dev/null | \
+                    awk '/online:/ { print ($2 == "yes") ? 1 : 0; exit }')
+            fi
+            ;;
+        freebsd)
+            online=$(sysctl_get hw.acpi.acline || true)
+            ;;
+        openbsd|netbsd)
+            online=$(apm -a 2>/dev/null || true)
+            ;;
+        macos)
+            online=$(pmset -g batt 2>/dev/null | awk 'NR == 1 { print /AC Power/ ? 1 : 0 }')
+            ;;
+        windows)
+            online=$(windows_battery_status | awk '$1 == "online" { print ($2 == "True") ? 1 : 0; exit }')
+            ;;
+    esac
+    case "$online" in
+        1) echo online ;;
+        0) echo offline ;;
+    esac
+}
+
+# Charging policy: what sample and log do when started on AC power, where
+# battery readings describe charging rather than drain. Set with --charging
+# or "policy" in the [charging] section of batlab.toml:
+#   refuse    exit with an error
+#   prompt    ask whether to continue; refuse without a terminal (default)
+#   wait      wait quietly until AC is disconnected
+#   annotate  continue, recording an ac_power annotation in the run
+CHARGING_POLICY=""
+CHARGING_POLL=5
+CHARGING_ON_AC=""
+
+# What log does when AC is connected during a run (--on-ac, or "on_ac" in
+# the [charging] section):
+#   annotate  keep logging; samples carry on_ac (default)
+#   pause     record no samples until AC is disconnected again
+#   abort     stop logging with stop reason ac_connected
+ON_AC_POLICY=""
+
+# Apply the charging policy; returns 1 when the command should not go on.
+# Sets CHARGING_ON_AC when continuing on AC power.
+check_charging() {
+    local policy="${CHARGING_POLICY:-$(config_get charging policy)}"
+    policy="${policy:-prompt}"
+    CHARGING_ON_AC=""
+
+    case "$policy" in
+        refuse|prompt|wait|annotate) ;;
+        *)
+            log_error "Unknown charging policy: $policy (expected refuse, prompt, wait or annotate)"
+            return 1
+            ;;
+    esac
+
+    [ "$(get_ac_state)" = "online" ] || return 0
+
+    case "$policy" in
+        refuse)
+            log_error "AC power is connected; unplug it to measure discharge (charging policy: refuse)"
+            return 1
+            ;;
+        prompt)
+            if [ ! -t 0 ]; then
+                log_error "AC power is connected and there is no terminal to ask; use --charging to choose a policy"
+                return 1
+            fi
+            printf "AC power is connected, so readings will not show discharge. Continue anyway? [y/N] " >&2
+            local answer=""
+            read -r answer || true
+            case "$answer" in
+                y|Y|yes|YES) CHARGING_ON_AC=1 ;;
+                *) return 1 ;;
+            esac
+            ;;
+        wait)
+            while [ "$(get_ac_state)" = "online" ]; do
+                sleep "$CHARGING_POLL"
+            done
+            ;;
+        annotate)
+            log_warn "AC power is connected; continuing (charging policy: annotate)"
+            CHARGING_ON_AC=1
+            ;;
+    esac
+}
+
+# Settling after unplugging: many batteries report garbage rates for the
+# first 30-60 s on battery. Whenever AC is disconnected during a run (not
+# at its start, which would leave short runs with nothing to measure), the
+# run is marked as settling until the watts
+# of the last SETTLE_WINDOW seconds stay within SETTLE_TOLERANCE percent of
+# their mean, or SETTLE_TIMEOUT seconds pass (--settle, [settle] timeout;
+# 0 disables). The interval is recorded as settle_start and settle_end
+# annotations, and reports leave it out.
+SETTLE_TIMEOUT=""
+SETTLE_WINDOW=10
+SETTLE_TOLERANCE=10
+SETTLE_SINCE=""
+SETTLE_READINGS=""
+AC_STATE=""
+
+settle_begin() {
+    [ "$SETTLE_TIMEOUT" -gt 0 ] || return 0
+    SETTLE_SINCE=$(date +%s)
+    SETTLE_READINGS=""
+    annotate "$1" settle_start "$2"
+}
+
+# Feed one sample to the settle check: settle_tick RUN_ID SAMPLE_JSON
+settle_tick() {
+    [ -n "$SETTLE_SINCE" ] || return 0
+    local now=$(date +%s)
+    local watts=$(printf '%s\n' "$2" | awk "$AWK_LIB"'{ print json_num($0, "watts") }')
+    if [ -n "$watts" ]; then
+        SETTLE_READINGS=$(printf '%s\n%s %s\n' "$SETTLE_READINGS" "$now" "$watts" | \
+            awk -v now="$now" -v win="$SETTLE_WINDOW" 'NF == 2 && $1 > now - win')
+    fi
+
+    local settled=$(printf '%s\n' "$SETTLE_READINGS" | awk -v win="$SETTLE_WINDOW" -v tol="$SETTLE_TOLERANCE" '
+    NF == 2 {
+        if (n++ == 0) { first = $1; lo = hi = $2 }
+        if ($2 < lo) lo = $2
+        if ($2 > hi) hi = $2
+        sum += $2; last = $1
+    }
+    END { exit !(n >= 3 && last - first >= win * 0.8 && sum > 0 && (hi - lo) <= tol / 100 * sum / n) }' && echo 1)
+
+    local elapsed=$((now - SETTLE_SINCE))
+    if [ -n "$settled" ]; then
+        log_info "Battery readings settled after ${elapsed}s"
+        annotate "$1" settle_end "settled after ${elapsed}s" "{\"settled\": true, \"seconds\": $elapsed}"
+        SETTLE_SINCE=""
+    elif [ "$elapsed" -ge "$SETTLE_TIMEOUT" ]; then
+        log_warn "Battery readings did not settle within ${SETTLE_TIMEOUT}s; measuring anyway"
+        annotate "$1" settle_end "not settled after ${elapsed}s" "{\"settled\": false, \"seconds\": $elapsed}"
+        SETTLE_SINCE=""
+    fi
+}
+
+# Annotate AC adapter changes during a run; unplugging starts a settle phase.
+# AC_PAUSED is set while the on-AC policy pauses sampling; returns 1 when
+# it says to abort.
+AC_PAUSED=""
+
+check_ac_transition() {
+    local state=$(get_ac_state)
+    if [ -n "$state" ] && [ -n "$AC_STATE" ] && [ "$state" != "$AC_STATE" ]; then
+        if [ "$state" = "online" ]; then
+            case "$ON_AC_POLICY" in
+                pause)
+                    log_warn "AC power connected during the run; pausing until it is disconnected"
+                    AC_PAUSED=1
+                    ;;
+                abort) log_error "AC power connected during the run; stopping (--on-ac abort)" ;;
+                *) log_warn "AC power connected during the run" ;;
+            esac
+            annotate "$1" ac_connected "" "{\"policy\": \"${ON_AC_POLICY:-annotate}\"}"
+            [ "$ON_AC_POLICY" != "abort" ] || return 1
+        else
+            log_info "AC power disconnected${AC_PAUSED:+; resuming}"
+            AC_PAUSED=""
+            annotate "$1" ac_disconnected ""
+            settle_begin "$1" "AC disconnected"
+        fi
+    fi
+    [ -z "$state" ] || AC_STATE="$state"
+}
+
+# Gaps while logging: no sample for more than five sample intervals (the
+# threshold gap_stats uses by default) means the machine was suspended or
+# the logger stalled. The gap is recorded as a gap annotation when sampling
+# resumes, and readings settle again as after unplugging.
+#   check_gap RUN_ID HZ
+GAP_LAST=""
+GAP_COUNT=0
+GAP_SECONDS=0
+
+check_gap() {
+    local now=$(now_ms)
+    local gap=""
+    if [ -n "$GAP_LAST" ]; then
+        gap=$(awk -v ms="$((now - GAP_LAST))" -v hz="$2" 'BEGIN { if (ms > 5000 / hz) printf "%.0f", ms / 1000 }')
+    fi
+    GAP_LAST="$now"
+    [ -n "$gap" ] || return 0
+
+    GAP_COUNT=$((GAP_COUNT + 1))
+    GAP_SECONDS=$((GAP_SECONDS + gap))
+    log_warn "No samples for ${gap}s (suspended or stalled); recording a gap"
+    annotate "$1" gap "no samples for ${gap}s" "{\"seconds\": $gap}"
+    settle_begin "$1" "resumed after a gap"
+}
+
+# Samples outside the "start end" epoch intervals in INTERVALS_FILE; an
+# interval without an end runs to the end of the stream
+drop_intervals() {
+    awk -F'\t' -v intervals="$1" '
+    BEGIN { while ((getline line < intervals) > 0) { split(line, f, " "); s[++n] = f[1]; e[n] = f[2] } }
+    {
+        for (i = 1; i <= n; i++) if ($1 >= s[i] && (e[i] == "" || $1 < e[i])) next
+        print
+    }'
+}
+
+# CPU frequency residency: time spent at each frequency, which explains why
+# configs with equal average load differ in power. Linux keeps cumulative
+# per-CPU time-in-state counters; on FreeBSD dev.cpu.0.freq is read instead
+# and the time since the last reading credited to that level. Every
+# FREQ_INTERVAL seconds the residency since the previous reading is appended
+# to data/<run_id>.freq.jsonl and added to the run total for the summary.
+FREQ_INTERVAL=10
+FREQ_SOURCE=""
+FREQ_PREV=""
+FREQ_LAST_T=""
+FREQ_TOTAL=""
+
+# Print cumulative "mhz seconds" lines summed over all CPUs (Linux)
+get_time_in_state() {
+    set -- "$SYSFS"/devices/system/cpu/cpu[0-9]*/cpufreq/stats/time_in_state
+    [ -r "$1" ] || return 1
+    # Counters are in units of 10 ms
+    cat "$@" 2>/dev/null | awk '
+        NF == 2 { t[int($1 / 1000)] += $2 / 100 }
+        END { for (f in t) printf "%d %.2f\n", f, t[f] }' | sort -n
+}
+
+# Pick a residency source and take the first reading
+freq_init() {
+    FREQ_SOURCE=""
+    FREQ_TOTAL=""
+    FREQ_LAST_T=$(date +%s)
+    metric_enabled freq || return 0
+    if FREQ_PREV=$(get_time_in_state) && [ -n "$FREQ_PREV" ]; then
+        FREQ_SOURCE=time_in_state
+    elif FREQ_PREV=$(sysctl -n dev.cpu.0.freq 2>/dev/null) && [ -n "$FREQ_PREV" ]; then
+        FREQ_SOURCE=sysctl
+    fi
+}
+
+# Call after each sample; FORCE (second argument) reads regardless of the
+# interval, for the final reading when logging stops
+freq_tick() {
+    [ -n "$FREQ_SOURCE" ] || return 0
+    local now=$(date +%s)
+    if [ -z "$2" ] && [ $((now - FREQ_LAST_T)) -lt "$FREQ_INTERVAL" ]; then
+        return 0
+    fi
+
+    local current delta
+    case "$FREQ_SOURCE" in
+        time_in_state)
+            current=$(get_time_in_state) || return 0
+            delta=$(printf '%s\n--\n%s\n' "$FREQ_PREV" "$current" | awk '
+                $1 == "--" { cur = 1; next }
+                !cur { prev[$1] = $2; next }
+                $2 - prev[$1] > 0 { printf "%d %.2f\n", $1, $2 - prev[$1] }')
+            ;;
+        sysctl)
+            current=$(sysctl -n dev.cpu.0.freq 2>/dev/null) || return 0
+            delta="$FREQ_PREV $((now - FREQ_LAST_T))"
+            ;;
+    esac
+    FREQ_PREV="$current"
+    FREQ_LAST_T="$now"
+    [ -n "$delta" ] || return 0
+
+    printf '%s\n' "$delta" | awk -v t="$(generate_timestamp)" '
+        $2 > 0 { out = out (out == "" ? "" : ", ") "\"" $1 "\": " $2 }
+        END { if (out != "") printf "{\"t\": \"%s\", \"residency_s\": {%s}}\n", t, out }' \
+        >> "${DATA_DIR}/$1.freq.jsonl"
+    FREQ_TOTAL=$(printf '%s\n%s\n' "$FREQ_TOTAL" "$delta" | awk '
+        NF == 2 { t[$1] += $2 }
+        END { for (f in t) printf "%d %.2f\n", f, t[f] }' | sort -n)
+}
+
+# Wireless link telemetry. The signal level and 802.11 power-save state of
+# the Wi-Fi interface are read every WIFI_INTERVAL seconds (iw and ifconfig
+# are too slow to run at higher sampling rates) and added to each sample
+# until the next reading as wifi_rssi and wifi_ps.
+WIFI_INTERVAL=60
+WIFI_IFACE=""
+WIFI_FIELDS=""
+WIFI_CHECKED=""
+
+# Print the wireless interface to report on: [wifi] interface, or the first
+# one the system lists
+detect_wifi_interface() {
+    local iface=$(config_get wifi interface)
+    if [ -n "$iface" ]; then
+        echo "$iface"
+        return 0
+    fi
+    case "$(detect_platform)" in
+        linux)
+            [ -r /proc/net/wireless ] || return 0
+            awk 'NR > 2 { sub(/:.*/, "", $1); print $1; exit }' /proc/net/wireless
+            ;;
+        freebsd)
+            for iface in $(ifconfig -l 2>/dev/null); do
+                case "$iface" in
+                    wlan*) echo "$iface"; return 0 ;;
+                esac
+            done
+            ;;
+    esac
+}
+
+# Print the sample fields for wireless interface IFACE, e.g.
+#   , "wifi_rssi": -56, "wifi_ps": true
+# On Linux the level is in dBm (/proc/net/wireless); FreeBSD reports the
+# RSSI of the associated access point in dB above the noise floor
+get_wifi_fields() {
+    local iface="$1" rssi="" ps=""
+    case "$(detect_platform)" in
+        linux)
+            if [ -r /proc/net/wireless ]; then
+                rssi=$(awk -v i="$iface" 'NR > 2 { n = $1; sub(/:.*/, "", n) }
+                    n == i { v = $4; sub(/\.$/, "", v); print v + 0; exit }' /proc/net/wireless)
+            fi
+            if command -v iw >/dev/null 2>&1; then
+                ps=$(iw dev "$iface" get power_save 2>/dev/null | awk '
+                    /Power save:/ { print ($3 == "on") ? "true" : "false" }')
+            elif command -v iwconfig >/dev/null 2>&1; then
+                ps=$(iwconfig "$iface" 2>/dev/null | awk -F'Power Management:' '
+                    NF > 1 { print ($2 ~ /^on/) ? "true" : "false" }')
+            fi
+            ;;
+        freebsd)
+            rssi=$(ifconfig "$iface" list sta 2>/dev/null | awk '
+                NR == 1 { for (i = 1; i <= NF; i++) if ($i == "RSSI") c = i; next }
+                c { print $c + 0; exit }')
+            ps=$(ifconfig -v "$iface" 2>/dev/null | awk '{
+                for (i = 1; i < NF; i++)
+                    if ($i == "powersavemode") { print ($(i + 1) == "OFF") ? "false" : "true"; exit }
+            }')
+            ;;
+    esac
+    [ -z "$rssi" ] || printf ', "wifi_rssi": %s' "$rssi"
+    [ -z "$ps" ] || printf ', "wifi_ps": %s' "$ps"
+}
+
+# Call before each sample; refreshes WIFI_FIELDS once the interval is up
+wifi_tick() {
+    [ -n "$WIFI_IFACE" ] || return 0
+    local now=$(date +%s)
+    if [ -n "$WIFI_CHECKED" ] && [ $((now - WIFI_CHECKED)) -lt "$WIFI_INTERVAL" ]; then
+        return 0
+    fi
+    WIFI_FIELDS=$(get_wifi_fields "$WIFI_IFACE")
+    WIFI_CHECKED="$now"
+}
+
+# Intel RAPL energy counters (Linux powercap; also AMD on recent kernels).
+# The package, core, uncore and DRAM counters are read before each sample
+# and the power over the interval since the previous reading is added to
+# the sample as rapl_pkg_w, rapl_core_w, rapl_uncore_w and rapl_dram_w,
+# much finer-grained than the battery's own power readings. energy_uj is
+# readable only by root on most kernels; without it the fields are left out.
+RAPL_PREV=""
+RAPL_PREV_MS=""
+RAPL_FIELDS=""
+
+# Print "zone domain energy_uj max_energy_range_uj" per RAPL zone, domain
+# being pkg (summed over packages), core, uncore or dram
+get_rapl_energy() {
+    local zone name domain
+    for zone in "$SYSFS"/class/powercap/intel-rapl:*; do
+        [ -r "$zone/energy_uj" ] || continue
+        name=$(cat "$zone/name" 2>/dev/null)
+        case "$name" in
+            package-*) domain=pkg ;;
+            core|uncore|dram) domain="$name" ;;
+            *) continue ;;
+        esac
+        printf '%s %s %s %s\n' "${zone##*/}" "$domain" "$(cat "$zone/energy_uj")" \
+            "$(cat "$zone/max_energy_range_uj" 2>/dev/null || echo 0)"
+    done
+}
+
+# Call before each sample; sets RAPL_FIELDS from the counters' change since
+# the previous call, allowing for counters that wrapped
+rapl_tick() {
+    metric_enabled rapl || return 0
+    local now=$(now_ms)
+    local current=$(get_rapl_energy)
+    RAPL_FIELDS=""
+    if [ -n "$current" ] && [ -n "$RAPL_PREV" ]; then
+        RAPL_FIELDS=$(printf '%s\n--\n%s\n' "$RAPL_PREV" "$current" | awk -v ms="$((now - RAPL_PREV_MS))" '
+            $1 == "--" { cur = 1; next }
+            !cur { prev[$1] = $3; next }
+            ($1 in prev) && ms > 0 {
+                d = $3 - prev[$1]
+                if (d < 0) d += $4
+                if (!($2 in w)) order[++n] = $2
+                w[$2] += d / (ms * 1000)
+            }
+            END { for (i = 1; i <= n; i++) printf ", \"rapl_%s_w\": %.3f", order[i], w[order[i]] }')
+    fi
+    RAPL_PREV="$current"
+    RAPL_PREV_MS="$now"
+}
+
+# Disk and network throughput. The cumulative disk (get_disk_io_kb) and
+# network (get_net_io_kb) counters are read before each sample and their
+# rates since the previous reading added to the sample as disk_kbps and
+# net_kbps, so I/O activity that confounds power comparisons is on record
+# next to the watts it cost.
+IO_PREV=""
+IO_PREV_MS=""
+IO_FIELDS=""
+
+# Cumulative KB received and sent by all interfaces but loopback since boot,
+# or nothing when unavailable
+get_net_io_kb() {
+    case "$(detect_platform)" in
+        linux)
+            [ -r /proc/net/dev ] || return 0
+            awk 'NR > 2 {
+                sub(/^[ \t]+/, ""); split($0, f, ":")
+                if (f[1] == "lo") next
+                split(f[2], v, " "); s += v[1] + v[9]; n++
+            } END { if (n) printf "%.0f\n", s / 1024 }' /proc/net/dev
+            ;;
+        freebsd)
+            # Link-level rows of netstat -ib; Ibytes and Obytes are counted
+            # from the end since Address is empty for some interfaces
+            command -v netstat >/dev/null 2>&1 || return 0
+            netstat -ibn 2>/dev/null | awk '
+                $3 ~ /^<Link/ && $1 !~ /^lo[0-9]/ { s += $(NF - 4) + $(NF - 1); n++ }
+                END { if (n) printf "%.0f\n", s / 1024 }'
+            ;;
+    esac
+}
+
+# Call before each sample; sets IO_FIELDS from the counters' change since
+# the previous call. A counter that went backwards (reset, or a device
+# removed) gives no rate for that interval.
+io_tick() {
+    metric_enabled io || return 0
+    local now=$(now_ms)
+    local disk=$(get_disk_io_kb)
+    local net=$(get_net_io_kb)
+    local current="${disk:--} ${net:--}"
+    IO_FIELDS=""
+    if [ -n "$IO_PREV" ]; then
+        IO_FIELDS=$(printf '%s %s\n' "$IO_PREV" "$current" | awk -v ms="$((now - IO_PREV_MS))" '
+            ms > 0 {
+                if ($1 != "-" && $3 != "-" && $3 >= $1) printf ", \"disk_kbps\": %.1f", ($3 - $1) * 1000 / ms
+                if ($2 != "-" && $4 != "-" && $4 >= $2) printf ", \"net_kbps\": %.1f", ($4 - $2) * 1000 / ms
+            }')
+    fi
+    IO_PREV="$current"
+    IO_PREV_MS="$now"
+}
+
+# Core functionality
+collect_sample() {
+    # Resolve cached state here rather than inside command substitutions,
+    # which run in subshells and would discard it
+    resolve_sensors
+    load_collectors
+    load_probes
+    load_plugins
+    load_meter
+
+    local timestamp=$(sample_timestamp)
+    local battery_info=$(get_battery_info)
+    local percentage=$(echo "$battery_info" | cut -d',' -f1)
+    local watts=$(echo "$battery_info" | cut -d',' -f2)
+    local source=$(echo "$battery_info" | cut -d',' -f3)
+    if ! is_known_source "$source"; then
+        log_error "Battery backend reported unknown source: $source (expected one of: $SOURCES)"
+        return 1
+    fi
+    local cpu_load=$(metric_enabled cpu && get_cpu_load)
+    local ram_pct=$(metric_enabled memory && get_memory_usage)
+    local temp_c=$(metric_enabled temp && get_temperature)
+    local io_kb=$(metric_enabled io && get_disk_io_kb)
+    local extra=$(run_collectors)
+    local custom=$(run_probes)
+    local plugins=$(run_plugins)
+    local meter_w=$(read_meter)
+    local backlight_pct=$(metric_enabled backlight && get_backlight_pct)
+    local electrical=$(metric_enabled electrical && get_battery_electrical)
+    local state=$(get_battery_state)
+
+    # Readings that could not be taken are null, never empty
+    : "${percentage:=null}" "${watts:=null}" "${cpu_load:=null}" "${ram_pct:=null}" "${temp_c:=null}"
+    if [ -n "$WARN_LOG" ]; then
+        [ "$percentage" != null ] || log_warn "Battery percentage unavailable"
+        [ "$watts" != null ] || log_warn "Battery power unavailable"
+        [ "$cpu_load" != null ] || ! metric_enabled cpu || log_warn "CPU load unavailable"
+        [ "$ram_pct" != null ] || ! metric_enabled memory || log_warn "Memory usage unavailable"
+        [ "$temp_c" != null ] || ! metric_enabled temp || log_warn "Temperature unavailable"
+    fi
+
+    # Optional readings, present only when taken
+    local optional=""
+    if [ -n "$io_kb" ]; then
+        optional=", \"io_kb\": $io_kb"
+    fi
+    if [ -n "$meter_w" ]; then
+        optional="$optional, \"meter_w\": $meter_w"
+    fi
+    if [ -n "$backlight_pct" ]; then
+        optional="$optional, \"backlight_pct\": $backlight_pct"
+    fi
+    optional="$optional$(printf '%s' "$electrical" | awk -F, '{
+        if ($1 != "") printf ", \"voltage_v\": %s", $1
+        if ($2 != "") printf ", \"current_a\": %s", $2
+        if ($3 != "") printf ", \"battery_temp_c\": %s", $3
+    }')"
+    if [ -n "$extra" ]; then
+        extra=", \"extra\": {$extra}"
+    fi
+    if [ -n "$custom" ]; then
+        extra="$extra, \"custom\": {$custom}"
+    fi
+    if [ -n "$plugins" ]; then
+        extra="$extra, \"plugins\": {$plugins}"
+    fi
+
+    # Format as JSON
+    cat << EOF
+{"t": "$timestamp", "pct": $percentage, "watts": $watts, "cpu_load": $cpu_load, "ram_pct": $ram_pct, "temp_c": $temp_c, "src": "$source", "state": "$state"$optional$extra}
+EOF
+}
+
+# Sampler: the loop behind batlab log, for other programs to embed.
+# Collects a sample on a fixed schedule and passes each to a callback:
+#
+#   on_sample() { printf '%s\n' "$1" >> run.jsonl; }
+#   SAMPLER_HZ=2
+#   SAMPLER_COUNT=10
+#   run_sampler on_sample
+#
+# ON_SAMPLE gets the sample, a JSON line with lat_ms and late added, and
+# stops the sampler by failing; ON_ERROR, if given, runs when collection
+# fails and stops it likewise. SAMPLER_SAMPLES and SAMPLER_ERRORS count
+# both as it goes. Without a callback failing the sampler runs until
+# SAMPLER_COUNT samples or SAMPLER_DURATION seconds, or forever when
+# neither is set. "select_metrics cpu,temp" before it limits the readings
+# taken (see METRICS).
+SAMPLER_HZ="$DEFAULT_HZ"
+SAMPLER_COUNT=""
+SAMPLER_DURATION=""
+SAMPLER_SAMPLES=0
+SAMPLER_ERRORS=0
+
+run_sampler() {
+    local on_sample="$1"
+    local on_error="$2"
+    PLATFORM=$(detect_platform)
+    resolve_sensors
+    load_collectors
+    load_probes
+    load_plugins
+    meter_start
+    warn_init
+
+    # Samples are scheduled on a fixed grid from the first one rather than
+    # sleeping a full interval after each, so collection time does not
+    # stretch the sampling period
+    local interval_ms=$(echo "$SAMPLER_HZ" | awk '{ printf "%d", 1000 / $1 }')
+    local deadline=$(now_ms)
+    local end=""
+    if [ -n "$SAMPLER_DURATION" ]; then
+        end=$(($(date +%s) + SAMPLER_DURATION))
+    fi
+    local sample t0 t1 late
+    SAMPLER_SAMPLES=0
+    SAMPLER_ERRORS=0
+
+    while true; do
+        wifi_tick
+        rapl_tick
+        io_tick
+        sysctl_snapshot
+        t0=$(now_ms)
+        if sample=$(collect_sample); then
+            # A sample still being collected when the next was due made the
+            # schedule slip; rate calculations may want to discard it
+            t1=$(now_ms)
+            late=false
+            [ "$t1" -le $((deadline + interval_ms)) ] || late=true
+            sample="${sample%\}}, \"lat_ms\": $((t1 - t0)), \"late\": $late$WIFI_FIELDS$RAPL_FIELDS$IO_FIELDS}"
+            SAMPLER_SAMPLES=$((SAMPLER_SAMPLES + 1))
+            "$on_sample" "$sample" || break
+        else
+            SAMPLER_ERRORS=$((SAMPLER_ERRORS + 1))
+            if [ -n "$on_error" ]; then
+                "$on_error" || break
+            fi
+        fi
+        warn_tick
+
+        if [ -n "$SAMPLER_COUNT" ] && [ "$SAMPLER_SAMPLES" -ge "$SAMPLER_COUNT" ]; then
+            break
+        fi
+        if [ -n "$end" ] && [ "$(date +%s)" -ge "$end" ]; then
+            break
+        fi
+
+        # Skip the slots a slow sample overran instead of bunching up
+        deadline=$((deadline + interval_ms))
+        t1=$(now_ms)
+        while [ "$deadline" -le "$t1" ]; do
+            deadline=$((deadline + interval_ms))
+        done
+        sleep "$(awk -v ms="$((deadline - t1))" 'BEGIN { printf "%.3f", ms / 1000 }')"
+    done
+}
+
+# Sample logs are data/<run_id>.jsonl; sidecars such as annotations share
+# the extension and must be skipped when iterating runs
+is_sample_log() {
+    case "$1" in
+        *.annotations.jsonl|*.downsampled.jsonl|*.freq.jsonl) return 1 ;;
+    esac
+    return 0
+}
+
+# Paths of the sample logs in DATA_DIR, one per line
+sample_logs() {
+    local file
+    for file in "$DATA_DIR"/*.jsonl; do
+        if [ -f "$file" ] && is_sample_log "$file"; then
+            printf '%s\n' "$file"
+        fi
+    done
+}
+
+# The most recently written sample log in DATA_DIR, or nothing
+latest_sample_log() {
+    local file latest=""
+    for file in "$DATA_DIR"/*.jsonl; do
+        [ -f "$file" ] && is_sample_log "$file" || continue
+        if [ -z "$latest" ] || [ "$file" -nt "$latest" ]; then
+            latest="$file"
+        fi
+    done
+    [ -z "$latest" ] || printf '%s\n' "$latest"
+}
+
+# Annotations mark events and stretches of a run in the append-only
+# data/<run_id>.annotations.jsonl, one record per line:
+#   {"t": TIMESTAMP, "kind": KIND, "label": TEXT, "payload": {...}}
+# label is for people; payload, when present, is a JSON object with the
+# machine-readable details and always comes last. Writers go through
+# annotate and readers through read_annotations, so report, status and
+# validate agree on the format. Kinds are a controlled vocabulary; new
+# events must add theirs here (and to the man page):
+#   workload_start   batlab run started a workload; label is name and args
+#   workload_end     the workload finished; payload {status, reason}
+#   power_profile    power profile or TLP mode changed
+#   expect_band      rolling power left or re-entered the expected band;
+#                    payload {state: low|high|in, avg_w, low, high}
+#   failure          the run failed; label is the reason
+#   ac_power         logging started on AC power
+#   ac_connected     AC adapter plugged in during the run; payload {policy}
+#   ac_disconnected  AC adapter unplugged during the run
+#   gap              no samples for a while (suspend); payload {seconds}
+#   settle_start     readings are settling and excluded from reports
+#   settle_end       end of settling; payload {settled, seconds}
+#   critical_battery the battery reached the critical level and logging
+#                    stopped; payload {pct, level, action}
+#   mark             start of a phase of the run; label names the phase
+ANNOTATION_KINDS="workload_start workload_end power_profile expect_band failure ac_power ac_connected ac_disconnected gap settle_start settle_end critical_battery mark"
+
+# Append an annotation, with an optional payload given as a JSON object:
+#   annotate RUN_ID KIND LABEL [PAYLOAD]
+annotate() {
+    case " $ANNOTATION_KINDS " in
+        *" $2 "*) ;;
+        *) log_warn "Recording annotation of unknown kind: $2" ;;
+    esac
+    printf '{"t": "%s", "kind": "%s", "label": "%s"%s}\n' "$(generate_timestamp)" \
+        "$(json_escape "$2")" "$(json_escape "$3")" "${4:+, \"payload\": $4}" >> "${DATA_DIR}/$1.annotations.jsonl"
+}
+
+# Start phase LABEL of RUN_ID (boot, idle, stress, ...); reports can give
+# statistics per phase, from one mark to the next
+#   mark RUN_ID LABEL
+mark() {
+    annotate "$1" mark "$2"
+}
+
+# Print the annotations of RUN_ID, optionally only those of the given
+# kinds, in file order as
+#   epoch  kind  label  payload
+# payload is the JSON object as written, or empty. Lines without a valid
+# timestamp or kind are skipped (validate reports them).
+#   read_annotations RUN_ID [KIND...]
+read_annotations() {
+    local file="${DATA_DIR}/$1.annotations.jsonl"
+    shift
+    [ -f "$file" ] || return 0
+    awk -v kinds="$*" "$AWK_LIB"'
+    BEGIN { n = split(kinds, k, " "); for (i = 1; i <= n; i++) want[k[i]] = 1 }
+    {
+        t = iso_to_epoch(json_str($0, "t")); kind = json_str($0, "kind")
+        if (t == "" || kind == "" || (n && !(kind in want))) next
+        payload = ""
+        if (match($0, /"payload"[ \t]*:[ \t]*\{.*\}[ \t]*\}[ \t]*$/)) {
+            payload = substr($0, RSTART, RLENGTH)
+            sub(/^"payload"[ \t]*:[ \t]*/, "", payload)
+            sub(/[ \t]*\}[ \t]*$/, "", payload)
+        }
+        printf "%s\t%s\t%s\t%s\n", t, kind, json_str($0, "label"), payload
+    }' "$file"
+}
+
+# Sum the watts of a sample stream by local hour of day, as
+#   config  hour  samples  sum_w
+# so runs logged at different times of day show time-correlated
+# interference (backups, screen dimming). The local offset is the current
+# one, which is off by an hour for samples on the other side of a DST change.
+hour_sums() {
+    awk -F'\t' -v config="$1" -v offset="$(date +%z)" '
+    BEGIN {
+        sign = (substr(offset, 1, 1) == "-") ? -1 : 1
+        off = sign * (substr(offset, 2, 2) * 3600 + substr(offset, 4, 2) * 60)
+    }
+    $3 != "" {
+        h = int(((int($1) + off) % 86400 + 86400) % 86400 / 3600)
+        n[h]++; sum[h] += $3
+    }
+    END { for (h = 0; h < 24; h++) if (n[h]) printf "%s\t%d\t%d\t%.4f\n", config, h, n[h], sum[h] }'
+}
+
+# Split a sample stream into buckets of STEP seconds from the run start:
+#   bucket  start_s  end_s  samples  avg_w  start_pct  end_pct  pct_drop
+# The drop of each bucket runs to the first reading of the next bucket so
+# that no discharge falls between buckets.
+drain_buckets() {
+    awk -F'\t' -v step="$1" '
+    {
+        if (NR == 1) start = $1
+        b = int(($1 - start) / step)
+        if (!(b in n)) { order[++nb] = b; first_pct[b] = "" }
+        n[b]++
+        if ($3 != "") { nw[b]++; sum_w[b] += $3 }
+        if ($2 != "") { if (first_pct[b] == "") first_pct[b] = $2; last_pct[b] = $2 }
+    }
+    END {
+        for (i = 1; i <= nb; i++) {
+            b = order[i]
+            next_pct = (i < nb) ? first_pct[order[i + 1]] : last_pct[b]
+            drop = (first_pct[b] != "" && next_pct != "") ? sprintf("%.2f", first_pct[b] - next_pct) : ""
+            printf "%d\t%.0f\t%.0f\t%d\t%s\t%s\t%s\t%s\n", b, b * step, (b + 1) * step, n[b],
+                (nw[b] ? sprintf("%.2f", sum_w[b] / nw[b]) : ""), first_pct[b], last_pct[b], drop
+        }
+    }'
+}
+
+# Discharge curve: how long each whole battery percentage point lasted and
+# how much energy was drawn while at it.
+#   pct  seconds  energy_wh  avg_w
+# Only levels whose entry and exit were both observed are emitted, so the
+# first and last levels of a run are omitted. A rising percentage (charging)
+# restarts the curve. Intervals longer than GAP seconds (if given) are
+# holes in the data and add no energy.
+discharge_curve() {
+    awk -F'\t' -v gap="$1" '
+    {
+        if (cur != "" && prev_w != "" && $3 != "" && (gap == "" || $1 - prev_t <= gap))
+            energy += (prev_w + $3) / 2 * ($1 - prev_t)
+        if ($2 != "") {
+            lvl = int($2)
+            if (cur == "" || lvl > cur) {
+                cur = lvl; lvl_start = $1; energy = 0; entered = 0
+            } else if (lvl < cur) {
+                if (entered && $1 > lvl_start) {
+                    printf "%d\t%.0f\t%.4f\t%.2f\n", cur, $1 - lvl_start, energy / 3600, energy / ($1 - lvl_start)
+                }
+                cur = lvl; lvl_start = $1; energy = 0; entered = 1
+            }
+        }
+        prev_t = $1
+        prev_w = $3
+    }'
+}
+
+# Correlation sufficient statistics for a sample stream. For each metric
+# pair (watts/cpu, watts/temp, watts/ram, cpu/temp, cpu/ram, temp/ram,
+# watts/backlight) print n, sum_x, sum_y, sum_xx, sum_yy, sum_xy over samples
+# where both are present. Sums from several runs can be added together to
+# pool them into a group.
+correlation_sums() {
+    awk -F'\t' '
+    BEGIN { np = split("3:4 3:6 3:5 4:6 4:5 6:5 3:11", pairs, " ") }
+    {
+        for (p = 1; p <= np; p++) {
+            split(pairs[p], xy, ":")
+            x = $(xy[1]); y = $(xy[2])
+            if (x == "" || y == "") continue
+            n[p]++; sx[p] += x; sy[p] += y; sxx[p] += x * x; syy[p] += y * y; sxy[p] += x * y
+        }
+    }
+    END {
+        for (p = 1; p <= np; p++) {
+            printf "%s%d\t%.10g\t%.10g\t%.10g\t%.10g\t%.10g", (p > 1 ? "\t" : ""), n[p], sx[p], sy[p], sxx[p], syy[p], sxy[p]
+        }
+        printf "\n"
+    }'
+}
+
+# Turn rows of "scope name <correlation_sums...>" into Pearson coefficients
+# (see CORR_COLUMNS). Pairs with fewer than 3 samples or no variance are empty.
+correlation_matrix() {
+    awk -F'\t' '
+    {
+        out = $1 "\t" $2 "\t" $3
+        for (p = 0; p < 7; p++) {
+            i = 3 + p * 6
+            n = $i; sx = $(i + 1); sy = $(i + 2); sxx = $(i + 3); syy = $(i + 4); sxy = $(i + 5)
+            d = (n * sxx - sx * sx) * (n * syy - sy * sy)
+            out = out "\t" ((n >= 3 && d > 0) ? sprintf("%.3f", (n * sxy - sx * sy) / sqrt(d)) : "")
+        }
+        print out
+    }'
+}
+
+# Probable background interference (package updates, indexers): stretches
+# of at least MIN_S seconds where load is well above the run's median and,
+# when io_kb was recorded, disk throughput spikes too, outside any workload
+# markers. Reads the sample stream on stdin and "start end" workload
+# intervals (epoch seconds) from MARKERS.
+#   start_s  end_s  avg_cpu  avg_io_kbps
+interference_windows() {
+    awk -F'\t' -v min_s="$1" -v markers="$2" "$AWK_LIB"'
+    BEGIN {
+        while ((getline line < markers) > 0) {
+            split(line, m, " ")
+            nm++; ms[nm] = m[1]; me[nm] = m[2]
+        }
+    }
+    {
+        n++; t[n] = $1; cpu[n] = $4; io[n] = ""
+        if ($8 != "" && prev_io != "" && $1 > prev_t && $8 >= prev_io) io[n] = ($8 - prev_io) / ($1 - prev_t)
+        prev_io = $8; prev_t = $1
+    }
+    function in_workload(x,    i) {
+        for (i = 1; i <= nm; i++) if (x >= ms[i] && (me[i] == "" || x <= me[i])) return 1
+        return 0
+    }
+    function flush() {
+        if (ws != "" && t[we] - t[ws] >= min_s) {
+            printf "%.0f\t%.0f\t%.1f\t%s\n", t[ws] - t[1], t[we] - t[1], sc / k * 100, (ki ? sprintf("%.0f", si / ki) : "")
+        }
+        ws = ""; sc = 0; k = 0; si = 0; ki = 0
+    }
+    END {
+        for (i = 1; i <= n; i++) { if (cpu[i] != "") C[++nc] = cpu[i] + 0; if (io[i] != "") I[++ni] = io[i] + 0 }
+        if (nc < 3) exit
+        cmed = median(C, nc)
+        cthr = (2 * cmed > cmed + 0.5) ? 2 * cmed : cmed + 0.5
+        if (ni >= 3) { imed = median(I, ni); ithr = (4 * imed > 1024) ? 4 * imed : 1024 }
+        for (i = 1; i <= n; i++) {
+            hot = (cpu[i] != "" && cpu[i] >= cthr)
+            if (hot && ni >= 3) hot = (io[i] != "" && io[i] >= ithr)
+            if (hot && !in_workload(t[i])) {
+                if (ws == "") ws = i
+                we = i; sc += cpu[i]; k++
+                if (io[i] != "") { si += io[i]; ki++ }
+            } else flush()
+        }
+        flush()
+    }'
+}
+
+# Settle intervals of a run as "start end" epoch lines, from settle_start and
+# settle_end annotations; one left open (logging stopped while settling)
+# runs to the end of the log
+settle_intervals() {
+    read_annotations "$1" settle_start settle_end | awk -F'\t' '
+    $2 == "settle_start" { if (open == "") open = $1 }
+    $2 == "settle_end" && open != "" { print open, $1; open = "" }
+    END { if (open != "") print open }'
+}
+
+# Count the annotations of RUN_ID that flag a stretch of doubtful data: a
+# failure, AC power, power outside the expected band, or readings that
+# never settled. Runs recorded before annotations had payloads are judged
+# by their labels.
+run_flags() {
+    read_annotations "$1" failure ac_power ac_connected expect_band settle_end | awk -F'\t' "$AWK_LIB"'
+    $2 == "expect_band" { if ($4 != "" ? json_str($4, "state") != "in" : $3 !~ /^back in band/) n++; next }
+    $2 == "settle_end" { if ($4 != "" ? $4 ~ /"settled": false/ : $3 ~ /^not settled/) n++; next }
+    { n++ }
+    END { print n + 0 }'
+}
+
+# Workload marker intervals of a run as "start end" epoch lines, from
+# workload_start/workload_end annotations. An unterminated workload runs
+# to the end of the log.
+# Print the marks of RUN_ID as "epoch<TAB>label" lines for phase_summaries
+phase_marks() {
+    read_annotations "$1" mark | cut -f1,3
+}
+
+# Statistics per phase of the samples (run_samples rows) on stdin, the
+# phases starting at the marks in MARKS_FILE (phase_marks):
+#   phase  label  start_s  end_s  samples  avg_w  start_pct  end_pct  pct_drop  avg_cpu  avg_temp
+# Phase N starts at the Nth mark; samples before the first form phase 0,
+# labelled "start". Times are seconds from the first sample, and a phase
+# ends where the next one starts or at the last sample.
+phase_summaries() {
+    awk -F'\t' -v marks="$1" '
+    BEGIN {
+        while ((getline line < marks) > 0) {
+            split(line, m, "\t")
+            nm++; mt[nm] = m[1]; ml[nm] = m[2]
+        }
+    }
+    {
+        if (NR == 1) start = $1
+        while (cur < nm && $1 >= mt[cur + 1]) cur++
+        p = cur
+        if (!(p in n)) { order[++np] = p; from[p] = p ? mt[p] : $1 }
+        last[p] = $1; n[p]++
+        if ($3 != "") { nw[p]++; sum_w[p] += $3 }
+        if ($2 != "") { if (!(p in first_pct)) first_pct[p] = $2; last_pct[p] = $2 }
+        if ($4 != "") { nc[p]++; sum_c[p] += $4 * 100 }
+        if ($6 != "") { nt[p]++; sum_t[p] += $6 }
+    }
+    END {
+        for (i = 1; i <= np; i++) {
+            p = order[i]
+            to = (i < np) ? from[order[i + 1]] : last[p]
+            printf "%d\t%s\t%.0f\t%.0f\t%d\t%s\t%s\t%s\t%s\t%s\t%s\n", p, (p ? ml[p] : "start"),
+                from[p] - start, to - start, n[p], (nw[p] ? sprintf("%.2f", sum_w[p] / nw[p]) : ""),
+                first_pct[p], last_pct[p], ((p in first_pct) ? sprintf("%.2f", first_pct[p] - last_pct[p]) : ""),
+                (nc[p] ? sprintf("%.1f", sum_c[p] / nc[p]) : ""), (nt[p] ? sprintf("%.1f", sum_t[p] / nt[p]) : "")
+        }
+    }'
+}
+
+workload_intervals() {
+    read_annotations "$1" workload_start workload_end | awk -F'\t' '
+    $2 == "workload_start" { if (open != "") print open; open = $1 }
+    $2 == "workload_end" && open != "" { print open, $1; open = "" }
+    END { if (open != "") print open }'
+}
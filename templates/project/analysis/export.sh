@@ -0,0 +1,16 @@
+#!/bin/sh
+
+# Write the CSV exports the notebooks read into analysis/exports/.
+# Run from the project directory.
+
+set -e
+
+out="analysis/exports"
+mkdir -p "$out"
+
+batlab export > "$out/runs.csv"
+batlab export --group-by config --weight duration > "$out/groups.csv"
+batlab export --tidy --resample 1m > "$out/samples.csv"
+batlab export --discharge-curve > "$out/discharge_curve.csv"
+
+echo "Exports written to $out"
@@ -0,0 +1,126 @@
+#!/bin/sh
+
+# Record this laptop as an integration test fixture: the output of the
+# platform tools batlab reads and a copy of the sysfs files it reads, in the
+# layout tests/run.sh replays (see there). The expected values are taken
+# from replaying the fixture, so compare them with what the laptop itself
+# reports before committing it.
+#
+#   tests/capture.sh NAME     e.g. linux-upower-thinkpad-x1c9
+
+set -e
+
+TESTS_DIR=$(cd "$(dirname "$0")" && pwd)
+. "$(dirname "$TESTS_DIR")/lib/batlab/core.sh"
+
+if [ $# -ne 1 ]; then
+    echo "usage: $0 NAME" >&2
+    exit 1
+fi
+FIXTURE="$TESTS_DIR/fixtures/$1"
+if [ -e "$FIXTURE" ]; then
+    echo "$FIXTURE already exists" >&2
+    exit 1
+fi
+mkdir -p "$FIXTURE"
+
+# Append "$ CMD ARGS" and its output to the transcript, if the tool exists
+record() {
+    command -v "$1" >/dev/null 2>&1 || return 0
+    printf '$ %s\n' "$*" >> "$FIXTURE/commands.txt"
+    "$@" >> "$FIXTURE/commands.txt" 2>/dev/null || true
+}
+
+# Copy the regular files FILES... of a sysfs directory, which are mostly
+# symlinks into /sys/devices, as plain files
+copy_sysfs() {
+    local dir="$1"
+    shift
+    local target="$FIXTURE/sys${dir#/sys}"
+    local file
+    mkdir -p "$target"
+    for file in "$@"; do
+        if [ -f "$dir/$file" ]; then
+            cat "$dir/$file" > "$target/$file" 2>/dev/null || rm -f "$target/$file"
+        fi
+    done
+}
+
+record uname -s
+case "$(uname -s)" in
+    Linux)
+        for supply in /sys/class/power_supply/*; do
+            [ -d "$supply" ] || continue
+            copy_sysfs "$supply" type scope status online capacity energy_now energy_full \
+                energy_full_design power_now charge_now charge_full current_now voltage_now temp
+        done
+        for chip in /sys/class/hwmon/hwmon*; do
+            [ -d "$chip" ] || continue
+            copy_sysfs "$chip" name $(cd "$chip" && ls temp*_input 2>/dev/null)
+        done
+        for zone in /sys/class/thermal/thermal_zone*; do
+            [ -d "$zone" ] || continue
+            copy_sysfs "$zone" type temp
+        done
+        for dev in /sys/class/backlight/*; do
+            [ -d "$dev" ] || continue
+            copy_sysfs "$dev" brightness max_brightness
+        done
+        record busctl --system call org.freedesktop.UPower /org/freedesktop/UPower org.freedesktop.UPower EnumerateDevices
+        for device in $(busctl --system call org.freedesktop.UPower /org/freedesktop/UPower org.freedesktop.UPower \
+            EnumerateDevices 2>/dev/null | tr ' ' '\n' | tr -d '"' | grep 'battery_'); do
+            record busctl --system get-property org.freedesktop.UPower "$device" org.freedesktop.UPower.Device \
+                Percentage EnergyRate
+        done
+        if command -v upower >/dev/null 2>&1; then
+            record upower -e
+            for device in $(upower -e | grep -E 'battery_|line_power_'); do
+                record upower -i "$device"
+            done
+        fi
+        ;;
+    FreeBSD)
+        record sysctl -n hw.acpi.acline
+        record sysctl -n hw.acpi.battery.units
+        record sysctl -n hw.acpi.battery.life
+        record sysctl -n hw.acpi.battery.rate
+        record sysctl -n dev.cpu.0.temperature
+        record sysctl -n hw.acpi.thermal.tz0.temperature
+        record sysctl -n hw.acpi.video.lcd0.brightness
+        units=$(sysctl -n hw.acpi.battery.units 2>/dev/null || echo 0)
+        unit=0
+        while [ "$unit" -lt "$units" ]; do
+            record acpiconf -i "$unit"
+            unit=$((unit + 1))
+        done
+        ;;
+    CYGWIN*|MINGW*|MSYS*)
+        record powershell.exe -NoProfile -NonInteractive -Command "$WINDOWS_BATTERY_PS"
+        ;;
+    *)
+        echo "Capturing is supported on Linux, FreeBSD and Windows (Cygwin or MSYS2)" >&2
+        rm -rf "$FIXTURE"
+        exit 1
+        ;;
+esac
+
+if [ -f batlab.toml ] && grep -q '^\[sensors\]' batlab.toml; then
+    awk '/^\[/ { keep = ($0 == "[sensors]") } keep' batlab.toml > "$FIXTURE/batlab.toml"
+fi
+
+# Expected values from a replay of what was just recorded. No sample has
+# src "-", so the replay fails and prints the sample it parsed.
+printf 'src -\n' > "$FIXTURE/expected"
+sample=$("$TESTS_DIR/run.sh" "$FIXTURE" | sed -n 's/^    sample: //p')
+printf '%s\n' "$sample" | awk '{
+    n = split("pct watts src temp_c backlight_pct voltage_v current_a battery_temp_c", keys, " ")
+    for (i = 1; i <= n; i++)
+        if (match($0, "\"" keys[i] "\": (\"[^\"]*\"|[^,}]*)")) {
+            v = substr($0, RSTART, RLENGTH); sub(/^"[^"]*": /, "", v); gsub(/"/, "", v)
+            if (v != "null") print keys[i], v
+        }
+}' > "$FIXTURE/expected"
+
+echo "Recorded $FIXTURE"
+echo "Expected values (check against the laptop's own readings):"
+sed 's/^/  /' "$FIXTURE/expected"
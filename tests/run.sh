@@ -0,0 +1,121 @@
+#!/bin/sh
+
+# Integration tests: replay recorded tool output and sysfs trees from
+# tests/fixtures through the battery and temperature collectors and check
+# the sample batlab parses from them.
+#
+# Each fixture directory holds
+#   commands.txt  transcripts of the tools batlab runs: a "$ CMD ARGS" line
+#                 followed by what it printed, up to the next "$ " line
+#   sys/          the sysfs files the collectors read (Linux)
+#   batlab.toml   optional configuration, e.g. pinned [sensors]
+#   expected      "key value" lines the sample must carry
+#
+# Platform tools (TOOLS) are replaced by the transcripts; a tool without one
+# does not exist for that fixture, whatever the host has installed.
+#
+#   tests/run.sh [FIXTURE...]
+
+set -e
+
+TESTS_DIR=$(cd "$(dirname "$0")" && pwd)
+ROOT=$(dirname "$TESTS_DIR")
+BATLAB="$ROOT/bin/batlab"
+. "$ROOT/lib/batlab/core.sh"
+
+TOOLS="uname busctl upower acpiconf sysctl apm envstat ioreg pmset iostat vmstat usbconfig pciconf iw iwconfig powerprofilesctl tlp-stat powershell.exe powercfg.exe"
+
+WORK=$(mktemp -d)
+trap 'rm -rf "$WORK"' EXIT
+
+# The host PATH without TOOLS, as symlinks in one directory
+mkdir "$WORK/host"
+printf '%s\n' "$PATH" | tr ':' '\n' | while read -r dir; do
+    [ -d "$dir" ] || continue
+    for exe in "$dir"/*; do
+        name=${exe##*/}
+        case " $TOOLS " in
+            *" $name "*) continue ;;
+        esac
+        if [ -x "$exe" ] && [ ! -e "$WORK/host/$name" ]; then
+            ln -s "$exe" "$WORK/host/$name"
+        fi
+    done
+done
+
+# Stand-in for every tool with a transcript: prints the output recorded for
+# its exact arguments and fails for arguments that were not recorded
+cat > "$WORK/replay" << 'EOF'
+#!/bin/sh
+awk -v cmd="\$ ${0##*/}${*:+ $*}" '
+    /^\$ / { if (found) exit; found = ($0 == cmd); next }
+    found { print }
+    END { exit !found }' "$FIXTURE/commands.txt"
+EOF
+chmod +x "$WORK/replay"
+
+# Check the sample in SAMPLE against the "key value" lines of EXPECTED
+check_sample() {
+    awk -v sample="$1" "$AWK_LIB"'
+    NF >= 2 {
+        key = $1; want = $2
+        got = json_num(sample, key)
+        if (got == "") got = json_str(sample, key)
+        if (got == "" && sample ~ "\"" key "\": null") got = "null"
+        if (want ~ /^-?[0-9]+(\.[0-9]+)?$/ && got ~ /^-?[0-9]+(\.[0-9]+)?$/) ok = (want + 0 == got + 0)
+        else ok = (want == got)
+        if (!ok) { printf "    %s: expected %s, got %s\n", key, want, (got == "" ? "nothing" : got); bad = 1 }
+    }
+    END { exit bad }' "$2"
+}
+
+run_fixture() {
+    local fixture="$1"
+    local name=${fixture##*/}
+    local dir="$WORK/$name"
+    local tool sample
+
+    mkdir -p "$dir/bin" "$dir/run"
+    for tool in $TOOLS; do
+        if grep -q "^\$ $tool\( \|$\)" "$fixture/commands.txt"; then
+            ln -s "$WORK/replay" "$dir/bin/$tool"
+        fi
+    done
+    if [ -f "$fixture/batlab.toml" ]; then
+        cp "$fixture/batlab.toml" "$dir/run/"
+    fi
+
+    if sample=$(cd "$dir/run" && FIXTURE="$fixture" BATLAB_SYSFS="$fixture/sys" BATLAB_CONFIG=batlab.toml \
+        PATH="$dir/bin:$WORK/host" "$BATLAB" sample --charging annotate 2> "$dir/stderr") &&
+        check_sample "$sample" "$fixture/expected" > "$dir/failures"; then
+        echo "PASS  $name"
+        return 0
+    fi
+    echo "FAIL  $name"
+    if [ -s "$dir/failures" ]; then
+        cat "$dir/failures"
+        echo "    sample: $sample"
+    else
+        sed 's/^/    /' "$dir/stderr"
+    fi
+    return 1
+}
+
+if [ $# -eq 0 ]; then
+    set -- "$TESTS_DIR"/fixtures/*/
+fi
+
+passed=0
+failed=0
+for fixture in "$@"; do
+    fixture=$(cd "$fixture" && pwd)
+    if run_fixture "$fixture"; then
+        passed=$((passed + 1))
+    else
+        failed=$((failed + 1))
+    fi
+done
+
+echo ""
+echo "$passed passed, $failed failed"
+[ "$failed" -eq 0 ]
@@ -0,0 +1,144 @@
+#!/bin/sh
+
+# Constant-power workload - paces CPU load to hold a target power draw
+# This workload follows the samples of the run being logged, which batlab run
+# passes in BATLAB_SAMPLES, and adjusts its duty cycle after each one so the
+# system draws the same watts on every OS under test (iso-power comparisons)
+
+# Default values
+watts=""
+duration=3600  # Default 1 hour
+
+# Parse command line arguments
+while [ $# -gt 0 ]; do
+    case "$1" in
+        --watts)
+            watts="$2"
+            shift 2
+            ;;
+        --duration)
+            duration="$2"
+            shift 2
+            ;;
+        --help|-h)
+            echo "Usage: $0 --watts WATTS [--duration SECONDS]"
+            echo "  --watts     Power draw to hold, in watts"
+            echo "  --duration  How long to run (default: 3600 seconds = 1 hour)"
+            echo ""
+            echo "Must be started with 'batlab run' while a run is being logged."
+            echo ""
+            echo "Example: $0 --watts 12 --duration 1800  # Hold 12 W for 30 minutes"
+            exit 0
+            ;;
+        *)
+            echo "Unknown option: $1" >&2
+            echo "Use --help for usage information"
+            exit 1
+            ;;
+    esac
+done
+
+# Validate target
+if ! awk -v w="$watts" 'BEGIN { exit !(w ~ /^[0-9]+(\.[0-9]+)?$/ && w > 0) }'; then
+    echo "Error: --watts expects a positive number of watts" >&2
+    exit 1
+fi
+if [ -z "$BATLAB_SAMPLES" ] || [ ! -f "$BATLAB_SAMPLES" ]; then
+    echo "Error: no sample stream; start this workload with 'batlab run' while logging" >&2
+    exit 1
+fi
+
+echo "Holding ${watts} W for $duration seconds ($(($duration / 60)) minutes)..."
+echo "Following samples of run ${BATLAB_RUN_ID:-?} in $BATLAB_SAMPLES"
+echo "Press Ctrl+C to stop"
+
+# Prevent system suspension during the paced test
+prevent_suspension() {
+    # Try systemd-inhibit first (most common on Linux)
+    if command -v systemd-inhibit >/dev/null 2>&1; then
+        echo "Preventing suspension with systemd-inhibit"
+        systemd-inhibit --what=sleep:idle --who=batlab-paced --why="Battery paced test in progress" sleep "$duration" &
+        inhibit_pid=$!
+        return 0
+    fi
+
+    # Try caffeinate on macOS
+    if command -v caffeinate >/dev/null 2>&1; then
+        echo "Preventing suspension with caffeinate"
+        caffeinate -i &
+        caffeinate_pid=$!
+        return 0
+    fi
+
+    echo "WARNING: No suspension prevention tool found - system may suspend during test"
+    return 1
+}
+
+# Duty cycle shared with the workers, 0.05 to 1 of every second spent working
+duty_file=$(mktemp)
+echo 0.5 > "$duty_file"
+
+# Cleanup function
+cleanup() {
+    echo "Re-enabling system suspension"
+    [ -n "$inhibit_pid" ] && kill "$inhibit_pid" 2>/dev/null
+    [ -n "$caffeinate_pid" ] && kill "$caffeinate_pid" 2>/dev/null
+
+    # Kill all paced worker processes
+    jobs -p | xargs -r kill 2>/dev/null
+    rm -f "$duty_file"
+    exit 0
+}
+
+# Set up signal handlers
+trap cleanup INT TERM
+
+# Start suspension prevention
+prevent_suspension
+
+# Get number of CPU cores
+ncpu=$(nproc 2>/dev/null || sysctl -n hw.ncpu 2>/dev/null || echo "1")
+echo "Using $ncpu CPU cores"
+
+end_time=$(($(date +%s) + duration))
+
+# Start CPU workers, each re-reading the duty cycle every second
+i=0
+while [ $i -lt "$ncpu" ]; do
+    (
+        while [ $(date +%s) -lt $end_time ]; do
+            work_time=$(cat "$duty_file" 2>/dev/null || echo 0.5)
+            sleep_time=$(awk "BEGIN {print 1 - $work_time}")
+            timeout "${work_time}s" sh -c 'while true; do echo "paced" | sha256sum >/dev/null 2>&1; done' 2>/dev/null || true
+            if [ "$sleep_time" != "0" ]; then
+                sleep "$sleep_time" 2>/dev/null || true
+            fi
+        done
+    ) &
+    i=$((i + 1))
+done
+
+# Adjust the duty cycle in proportion to the error of each new reading
+seen=$(wc -l < "$BATLAB_SAMPLES")
+while [ $(date +%s) -lt $end_time ]; do
+    sleep 1
+    lines=$(wc -l < "$BATLAB_SAMPLES")
+    [ "$lines" -ne "$seen" ] || continue
+    seen=$lines
+    reading=$(tail -n 1 "$BATLAB_SAMPLES" | sed -n 's/.*"watts": *\([0-9.]*\).*/\1/p')
+    [ -n "$reading" ] || continue
+    duty=$(awk -v d="$(cat "$duty_file")" -v w="$reading" -v target="$watts" 'BEGIN {
+        d += 0.5 * (target - w) / target
+        printf "%.2f", (d < 0.05) ? 0.05 : (d > 1) ? 1 : d
+    }')
+    echo "$duty" > "$duty_file"
+    echo "Power ${reading} W, target ${watts} W: duty cycle now $duty"
+done
+
+# Wait for all background jobs to complete
+wait
+
+echo "Paced workload completed"
+
+# Clean up
+cleanup